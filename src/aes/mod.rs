@@ -3,71 +3,192 @@
 
 use aead::{AeadCore, generic_array::GenericArray};
 use aes_gcm_siv::{
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit, OsRng, Payload},
     Aes256GcmSiv, Nonce // Or `Aes128GcmSiv`
 };
 use base64::{Engine as _, engine::general_purpose};
 use general_purpose::STANDARD_NO_PAD as BASE64;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Why a key can't be used or a message can't be decrypted
+#[derive(Debug)]
+pub enum CryptoError {
+    /// The key is not a base64 AES-256 key
+    InvalidKey,
+    /// The message is not valid base64
+    Base64(base64::DecodeError),
+    /// The message is too short, was changed or was encrypted with another key
+    Authentication,
+    /// The plaintext is not UTF-8
+    Utf8,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::InvalidKey => write!(f, "the key is not a base64 AES-256 key"),
+            CryptoError::Base64(e) => write!(f, "the message is not valid base64: {}", e),
+            CryptoError::Authentication => write!(f, "the message can't be decrypted with the room key"),
+            CryptoError::Utf8 => write!(f, "the message is not UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
 
 #[derive(Clone)]
 pub struct AES{
-    key: String
+    key: String,
+    raw_key: Vec<u8>,
+    cipher: Aes256GcmSiv,
 }
 
 impl AES{
     /// This function will create a new AES cipher with a random key if no key is provided.
-    /// 
-    pub fn new(key: Option<String>) -> Self{
-        if key.is_some(){
-            return AES{
-                key: key.unwrap()
-            };
+    /// # Arguments
+    /// * `key` - A base64 AES-256 key
+    /// # Errors
+    /// * `CryptoError::InvalidKey` - If the key is not a base64 AES-256 key
+    pub fn new(key: Option<String>) -> Result<Self, CryptoError>{
+        let key = match key{
+            Some(key) => key,
+            None => BASE64.encode(Aes256GcmSiv::generate_key(&mut OsRng)),
+        };
+        let raw_key = BASE64.decode(&key).map_err(|_| CryptoError::InvalidKey)?;
+        if raw_key.len() != 32{
+            return Err(CryptoError::InvalidKey);
         }
-        let key = Aes256GcmSiv::generate_key(&mut OsRng);
-        let key_base64 = BASE64.encode(key);
+        Ok(Self::from_raw(raw_key))
+    }
+
+    /// Creates a cipher from a 32 bytes key
+    fn from_raw(raw_key: Vec<u8>) -> Self{
+        let cipher = Aes256GcmSiv::new(GenericArray::from_slice(raw_key.as_slice()));
         AES{
-            key: key_base64
+            key: BASE64.encode(&raw_key),
+            raw_key,
+            cipher,
         }
     }
 
+    /// Derives a new key from ours, the same context always gives the same key
+    /// and different contexts give unrelated keys
+    /// # Arguments
+    /// * `context` - What the new key is for
+    pub fn derive(&self, context: &[u8]) -> AES{
+        //HMAC-SHA256 tags are 32 bytes, the size of an AES-256 key
+        Self::from_raw(self.sign(context))
+    }
+
     pub fn get_key(&self) -> &String{
         return &self.key;
     }
 
-    pub fn decrypt(&self, b64_cipher: String) -> String{
-        let key_try = &BASE64.decode(&self.key);
-        if key_try.is_err(){
-            return "".to_string();
-        }
-        let key_decode = key_try.as_ref().unwrap();
-        let key = GenericArray::from_slice(key_decode.as_slice());
-    
-        let b64_decode = BASE64.decode(b64_cipher).unwrap();
-        let nonce = &b64_decode[0..12];
-        let ciphertext = &b64_decode[12..b64_decode.len()];
-    
-        let cipher = Aes256GcmSiv::new(key);
-        let decrypted = cipher.decrypt(&Nonce::from_slice(nonce), ciphertext.as_ref()).unwrap_or(vec![0]);
-    
-        String::from_utf8(decrypted).unwrap()
+    /// Decrypts a base64 message
+    /// # Errors
+    /// * `CryptoError` - If the message is not base64, can't be decrypted with our key or it is not UTF-8
+    pub fn decrypt(&self, b64_cipher: String) -> Result<String, CryptoError>{
+        let b64_decode = BASE64.decode(b64_cipher).map_err(CryptoError::Base64)?;
+        let decrypted = self.decrypt_bytes(&b64_decode)?;
+        String::from_utf8(decrypted).map_err(|_| CryptoError::Utf8)
     }
     
     pub fn encrypt(&self, message: String) -> String{
-        let key_try = &BASE64.decode(&self.key);
-        if key_try.is_err(){
-            return "".to_string();
+        BASE64.encode(self.encrypt_bytes(message.as_bytes()))
+    }
+
+    /// Decrypts a binary message
+    /// The message is expected as follows:
+    /// <nonce 12 bytes><ciphertext variable size>
+    /// # Arguments
+    /// * `message` - The nonce and ciphertext
+    /// # Errors
+    /// * `CryptoError::Authentication` - If the message is too short or it fails the authentication
+    pub fn decrypt_bytes(&self, message: &[u8]) -> Result<Vec<u8>, CryptoError>{
+        if message.len() < 12{
+            return Err(CryptoError::Authentication);
         }
-        let key_decode = key_try.as_ref().unwrap();
-        let key = GenericArray::from_slice(key_decode.as_slice());
-    
+        let nonce = &message[0..12];
+        let ciphertext = &message[12..];
+        self.cipher.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| CryptoError::Authentication)
+    }
+
+    /// Encrypts a binary message using a random nonce
+    /// The result is serialized as follows:
+    /// <nonce 12 bytes><ciphertext variable size>
+    /// # Arguments
+    /// * `message` - The plaintext
+    /// # Returns
+    /// * `Vec<u8>` - The nonce and ciphertext
+    pub fn encrypt_bytes(&self, message: &[u8]) -> Vec<u8>{
         let nonce = &Aes256GcmSiv::generate_nonce(&mut OsRng);
-        let cipher = Aes256GcmSiv::new(key);
-        let ciphertext = cipher.encrypt(nonce, message.as_bytes().as_ref()).unwrap();
-    
+        //AES-GCM-SIV only refuses plaintexts over 64 GiB
+        let ciphertext = self.cipher.encrypt(nonce, message).expect("message too long to encrypt");
+
         let mut nonceciphertext = nonce.to_vec();
         nonceciphertext.extend_from_slice(&ciphertext);
-    
-        BASE64.encode(nonceciphertext)
+        nonceciphertext
+    }
+
+    /// Encrypts a binary message using a counter as the nonce, a counter must never be
+    /// used twice with the same key. The nonce isn't part of the result
+    /// # Arguments
+    /// * `counter` - The message number
+    /// * `aad` - Bytes sent along the message in the clear, they are authenticated too
+    /// * `message` - The plaintext
+    /// # Returns
+    /// * `Vec<u8>` - The ciphertext and its 16 bytes tag
+    pub fn encrypt_counter(&self, counter: u64, aad: &[u8], message: &[u8]) -> Vec<u8>{
+        let payload = Payload { msg: message, aad };
+        self.cipher.encrypt(&counter_nonce(counter), payload).expect("message too long to encrypt")
+    }
+
+    /// Decrypts a binary message made by ```encrypt_counter```
+    /// # Arguments
+    /// * `counter` - The message number
+    /// * `aad` - The bytes sent in the clear along the message
+    /// * `ciphertext` - The ciphertext and its tag
+    /// # Errors
+    /// * `CryptoError::Authentication` - If the ciphertext, the counter or the clear bytes were changed
+    pub fn decrypt_counter(&self, counter: u64, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError>{
+        let payload = Payload { msg: ciphertext, aad };
+        self.cipher.decrypt(&counter_nonce(counter), payload).map_err(|_| CryptoError::Authentication)
     }
 
-}
\ No newline at end of file
+    /// Computes the HMAC-SHA256 of a message keyed with our key
+    /// # Arguments
+    /// * `message` - The bytes to authenticate
+    /// # Returns
+    /// * `Vec<u8>` - The 32 bytes tag
+    pub fn sign(&self, message: &[u8]) -> Vec<u8>{
+        let mut mac = self.mac();
+        mac.update(message);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Checks a tag made by ```sign```, the comparison takes the same time whatever the tag
+    /// # Arguments
+    /// * `message` - The authenticated bytes
+    /// * `tag` - The tag to check
+    pub fn verify(&self, message: &[u8], tag: &[u8]) -> bool{
+        let mut mac = self.mac();
+        mac.update(message);
+        mac.verify_slice(tag).is_ok()
+    }
+
+    fn mac(&self) -> HmacSha256{
+        <HmacSha256 as Mac>::new_from_slice(&self.raw_key).expect("HMAC accepts any key size")
+    }
+
+}
+
+/// The 12 bytes nonce of a message number, big endian
+fn counter_nonce(counter: u64) -> Nonce{
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
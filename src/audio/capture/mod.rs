@@ -1,36 +1,30 @@
 // SPDX-FileCopyrightText: Copyright 2023 Savi
 // SPDX-License-Identifier: GPL-3.0-only 
-use miniaudio::{Device, DeviceId, Format, ShareMode, DeviceConfig, DeviceType, Backend, Context};
-use std::{sync::{Arc, Mutex, Condvar, atomic::AtomicI32, mpsc::Sender}, fmt::format, net::UdpSocket};
+use miniaudio::{Device, DeviceId, Format, ShareMode, DeviceConfig, DeviceType, Backend};
+use std::{sync::{Arc, Mutex, Condvar, atomic::AtomicI32, mpsc::Sender}};
 use opus::{Encoder, Application, Channels, Bitrate};
-use rand::Rng;
-use std::sync::mpsc::channel;
+use crate::audio::{Audio, AudioError};
 pub struct AudioCapture{
     capture_arc: Arc<(Mutex<Vec<Vec<u8>>>, Condvar)>,
     capture_device: Device,
     intensity: Arc<AtomicI32>,
     threshold: Arc<AtomicI32>,
     encoder: Arc<Mutex<Encoder>>,
-    queue_port: u16,
-    conn_port: u16
 }
 impl AudioCapture {
     /// Creates a new AudioCapture instance
     /// # Arguments
+    /// * `backend` - The audio backend
     /// * `device_id` - The DeviceId of the device to use
     /// * `channels` - The number of channels to use
     /// * `sample_rate` - The sample rate to use
     /// * `encoder_bitrate` - The bitrate to use for the encoder
     /// * `active_threshold` - The RMS threshold to record and encode the sample
-    pub fn new(backend: Backend, device_id: DeviceId, channels: u32, sample_rate: u32, encoder_bitrate: i32, active_threshold: i32, tx: Sender<Vec<u8>>) -> Self{
-        let context = Context::new(&[backend], None).unwrap();
-        let queue_port = rand::thread_rng().gen_range(49152..65534);
-        let conn_port = rand::thread_rng().gen_range(49152..65534);
-        let bind_addr = format!("127.0.0.1:{}", queue_port);
-        let conn_addr = format!("127.0.0.1:{}", conn_port);
-        info!("Audio capture queue bound to {}", bind_addr);
-        let socket = UdpSocket::bind(bind_addr.clone()).unwrap();
-
+    /// * `tx` - Receives the encoded packets
+    /// # Errors
+    /// * `AudioError` - If the device or the encoder can't be created
+    pub fn new(backend: Backend, device_id: DeviceId, channels: u32, sample_rate: u32, encoder_bitrate: i32, active_threshold: i32, tx: Sender<Vec<u8>>) -> Result<Self, AudioError>{
+        let context = Audio::context(backend)?;
 
         let capture_arc = Arc::new((Mutex::new(Vec::new()), Condvar::new()));
         let capture_clone = capture_arc.clone();
@@ -52,14 +46,15 @@ impl AudioCapture {
         let encoder_channels = match channels {
             1 => Channels::Mono,
             2 => Channels::Stereo,
-            _ => panic!("Invalid channel count"),
+            _ => return Err(AudioError::Channels(channels)),
         };
-        let encoder = Arc::new(Mutex::new(Encoder::new(sample_rate, encoder_channels, Application::Voip).unwrap()));
-        encoder.lock().unwrap().set_bitrate(Bitrate::Bits(encoder_bitrate)).unwrap();
-        encoder.lock().unwrap().set_vbr(true).unwrap();
+        let mut new_encoder = Encoder::new(sample_rate, encoder_channels, Application::Voip)?;
+        new_encoder.set_bitrate(Bitrate::Bits(encoder_bitrate))?;
+        new_encoder.set_vbr(true)?;
+        let encoder = Arc::new(Mutex::new(new_encoder));
         let encoder_clone = encoder.clone();
 
-        let mut capture_device: Device = Device::new(Some(context), &config).unwrap();
+        let mut capture_device: Device = Device::new(Some(context), &config).map_err(AudioError::Device)?;
         capture_device.set_data_callback(move |_, _, input|{
             let input_samples = input.as_samples::<i16>();
             let num_samples = input_samples.len();
@@ -71,21 +66,28 @@ impl AudioCapture {
 
             //If the RMS is above the threshold, encode and push to the queue
             if rms > threshold_clone.load(std::sync::atomic::Ordering::Relaxed){
-                let encoded = encoder_clone.lock().unwrap().encode_vec(input_samples, num_samples).unwrap();
-                tx.send(encoded).unwrap();
+                //A sample the encoder can't take is dropped, the next one may go through
+                if let Ok(encoded) = encoder_clone.lock().unwrap().encode_vec(input_samples, num_samples){
+                    //Nobody listens anymore once the capture is being replaced
+                    let _ = tx.send(encoded);
+                }
             }
         });
-        AudioCapture { capture_arc,  capture_device, intensity, threshold, encoder, queue_port, conn_port }
+        Ok(AudioCapture { capture_arc,  capture_device, intensity, threshold, encoder })
     }
 
     /// Starts the capture device
-    pub fn start(&self){
-        self.capture_device.start().unwrap();
+    /// # Errors
+    /// * `AudioError::Device` - If the device can't be started
+    pub fn start(&self) -> Result<(), AudioError>{
+        self.capture_device.start().map_err(AudioError::Device)
     }
 
     /// Stops the capture device
-    pub fn stop(&self){
-        self.capture_device.stop().unwrap();
+    /// # Errors
+    /// * `AudioError::Device` - If the device can't be stopped
+    pub fn stop(&self) -> Result<(), AudioError>{
+        self.capture_device.stop().map_err(AudioError::Device)
     }
 
     /// Returns the capture arc
@@ -104,16 +106,11 @@ impl AudioCapture {
     }
 
     /// Changes the encoder bitrate
-    pub fn set_encoder_bitrate(&self, value: i32){
-        self.encoder.lock().unwrap().set_bitrate(Bitrate::Bits(value)).unwrap();
-    }
-
-    pub fn get_queue_addr(&self) -> String{
-        format!("127.0.0.1:{}", self.queue_port)
-    }
-
-    pub fn get_conn_addr(&self) -> String{
-        format!("127.0.0.1:{}", self.conn_port)
+    /// # Errors
+    /// * `AudioError::Opus` - If the encoder doesn't support the bitrate
+    pub fn set_encoder_bitrate(&self, value: i32) -> Result<(), AudioError>{
+        self.encoder.lock().unwrap().set_bitrate(Bitrate::Bits(value))?;
+        Ok(())
     }
 
 
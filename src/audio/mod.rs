@@ -2,6 +2,8 @@
 // SPDX-License-Identifier: GPL-3.0-only 
 
 use miniaudio::{Context, DeviceId, DeviceIdAndName, Backend};
+use std::fmt;
+use std::io;
 
 pub mod capture;
 pub mod playback;
@@ -11,16 +13,61 @@ pub enum DeviceKind{
     Capture,
     Playback,
 }
+
+/// Why an audio device or an audio link can't be used
+#[derive(Debug)]
+pub enum AudioError {
+    /// The audio backend can't be loaded or its devices can't be listed
+    Backend(miniaudio::Error),
+    /// There is no device with that name
+    DeviceNotFound(String),
+    /// The device can't be opened, started or stopped
+    Device(miniaudio::Error),
+    /// Only mono and stereo are supported
+    Channels(u32),
+    /// The Opus encoder or decoder refused its settings
+    Opus(opus::Error),
+    /// The sockets of an audio link failed
+    Io(io::Error),
+    /// The audio link was started already
+    AlreadyConnected,
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::Backend(e) => write!(f, "the audio backend can't be used: {:?}", e),
+            AudioError::DeviceNotFound(name) => write!(f, "audio device {} not found", name),
+            AudioError::Device(e) => write!(f, "the audio device failed: {:?}", e),
+            AudioError::Channels(channels) => write!(f, "{} channels are not supported, only mono and stereo", channels),
+            AudioError::Opus(e) => write!(f, "opus error: {}", e),
+            AudioError::Io(e) => write!(f, "audio socket error: {}", e),
+            AudioError::AlreadyConnected => write!(f, "the audio link is already up"),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {}
+
+impl From<opus::Error> for AudioError {
+    fn from(e: opus::Error) -> Self {
+        AudioError::Opus(e)
+    }
+}
+
+impl From<io::Error> for AudioError {
+    fn from(e: io::Error) -> Self {
+        AudioError::Io(e)
+    }
+}
+
 pub struct Audio {}
 impl Audio {
     /// Returns all the capture devices
-    pub fn get_input_devices(backend: Option<Backend>) -> Vec<(String, DeviceId)> {
-        let context = Context::new(&[
-            match backend {
-                Some(b) => b,
-                None => Backend::Null,
-            }
-        ], None).unwrap();
+    /// # Errors
+    /// * `AudioError::Backend` - If the backend can't be used
+    pub fn get_input_devices(backend: Option<Backend>) -> Result<Vec<(String, DeviceId)>, AudioError> {
+        let context = Self::context(backend.unwrap_or(Backend::Null))?;
         let mut inputs: Vec<(String, DeviceId)> = Vec::new();
         context
             .with_devices(|_, capture_devices| {
@@ -28,18 +75,15 @@ impl Audio {
                     inputs.push((device.name().to_string(), device.id().clone()));
                 }
             })
-            .expect("failed to get devices");
-        inputs
+            .map_err(AudioError::Backend)?;
+        Ok(inputs)
     }
 
     /// Returns all the playback devices
-    pub fn get_output_devices(backend: Option<Backend>) -> Vec<(String, DeviceId)> {
-        let context = Context::new(&[
-            match backend {
-                Some(b) => b,
-                None => Backend::Null,
-            }
-        ], None).unwrap();
+    /// # Errors
+    /// * `AudioError::Backend` - If the backend can't be used
+    pub fn get_output_devices(backend: Option<Backend>) -> Result<Vec<(String, DeviceId)>, AudioError> {
+        let context = Self::context(backend.unwrap_or(Backend::Null))?;
         let mut outputs: Vec<(String, DeviceId)> = Vec::new();
         context
             .with_devices(|playback_devices, _| {
@@ -47,13 +91,15 @@ impl Audio {
                     outputs.push((device.name().to_string(), device.id().clone()));
                 }
             })
-            .expect("failed to get devices");
-        outputs
+            .map_err(AudioError::Backend)?;
+        Ok(outputs)
     }
 
     /// Prints all the capture and playback devices (used for debugging)
-    pub fn print_devices() {
-        let context = Context::new(&[], None).unwrap();
+    /// # Errors
+    /// * `AudioError::Backend` - If no backend can be used
+    pub fn print_devices() -> Result<(), AudioError> {
+        let context = Context::new(&[], None).map_err(AudioError::Backend)?;
 
         context
             .with_devices(|playback_devices, capture_devices| {
@@ -67,12 +113,20 @@ impl Audio {
                     println!("\t{}: {}", idx, device.name());
                 }
             })
-            .expect("failed to get devices");
+            .map_err(AudioError::Backend)
     }
 
-    pub fn get_device_id(backend: String, name: &String, kind: DeviceKind) -> Option<DeviceId>{
+    /// Finds a device by name
+    /// # Arguments
+    /// * `backend` - The audio backend name
+    /// * `name` - The device name
+    /// * `kind` - Whether it is a capture or a playback device
+    /// # Errors
+    /// * `AudioError::Backend` - If the backend can't be used
+    /// * `AudioError::DeviceNotFound` - If the backend has no device with that name
+    pub fn get_device_id(backend: String, name: &String, kind: DeviceKind) -> Result<DeviceId, AudioError>{
         let backend = Self::backend_from_text(backend);
-        let context = Context::new(&[backend], None).unwrap();
+        let context = Self::context(backend)?;
         let mut id = None;
         context
             .with_devices(|playback_devices, capture_devices| {
@@ -92,9 +146,17 @@ impl Audio {
                 }
                 
             })
-            .expect("failed to get devices");
-        id
+            .map_err(AudioError::Backend)?;
+        id.ok_or_else(|| AudioError::DeviceNotFound(name.clone()))
+    }
+
+    /// Loads a single audio backend
+    /// # Errors
+    /// * `AudioError::Backend` - If the backend is not available on this system
+    pub fn context(backend: Backend) -> Result<Context, AudioError>{
+        Context::new(&[backend], None).map_err(AudioError::Backend)
     }
+
     pub fn backend_from_text(backend: String) -> Backend{
         match backend.as_str(){
             "PulseAudio" => Backend::PulseAudio,
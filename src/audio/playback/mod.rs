@@ -1,10 +1,12 @@
-use miniaudio::{Device, DeviceId, Format, ShareMode, DeviceConfig, DeviceType, Context, Backend};
-use std::{sync::{Arc, Mutex, Condvar}};
+use miniaudio::{Device, DeviceId, Format, ShareMode, DeviceConfig, DeviceType, Backend};
+use std::{sync::{Arc, Mutex, Condvar, atomic::{AtomicBool, Ordering}}};
 use opus::{Decoder, Channels};
+use crate::audio::{Audio, AudioError};
 
 pub struct AudioPlayback{
     playback_arc: Arc<(Mutex<Vec<Vec<u8>>>, Condvar)>,
     playback_device: Device,
+    running: Arc<AtomicBool>,
 }
 impl AudioPlayback {
 
@@ -27,17 +29,22 @@ impl AudioPlayback {
 
     /// Creates a new AudioPlayback instance
     /// # Arguments
+    /// * `backend` - The audio backend
     /// * `config` - The DeviceConfig to use
-    pub fn new(backend: Backend, config: DeviceConfig) -> Self{
+    /// # Errors
+    /// * `AudioError` - If the device or the decoder can't be created
+    pub fn new(backend: Backend, config: DeviceConfig) -> Result<Self, AudioError>{
         let playback_arc = Arc::new((Mutex::new(Vec::<Vec<u8>>::new()), Condvar::new()));
         let playback_clone = playback_arc.clone();
+        let running = Arc::new(AtomicBool::new(true));
+        let running_clone = running.clone();
         
         let decoder_channels = match config.playback().channels() {
             1 => Channels::Mono,
             2 => Channels::Stereo,
-            _ => panic!("Invalid channel count"),
+            channels => return Err(AudioError::Channels(channels)),
         };
-        let mut decoder = Decoder::new(config.sample_rate(), decoder_channels).unwrap();
+        let mut decoder = Decoder::new(config.sample_rate(), decoder_channels)?;
 
         //print config:
         let a = config.sample_rate();
@@ -45,8 +52,8 @@ impl AudioPlayback {
         let c = config.playback().format();
         let d = config.playback().share_mode();
         //TODO:FIX something is wrong with the device instance where the playback device shoud have 960 sample size it only shows 288 as sample size
-        let context = Context::new(&[backend], None).unwrap();
-        let mut playback_device: Device = Device::new(Some(context), &config).unwrap();
+        let context = Audio::context(backend)?;
+        let mut playback_device: Device = Device::new(Some(context), &config).map_err(AudioError::Device)?;
         let e = playback_device.playback().name();
 
         println!("Playback config: sample_rate: {}, channels: {}, format: {:?}, share_mode: {:?}, name: {}", a, b, c, d, e);
@@ -56,6 +63,10 @@ impl AudioPlayback {
             //Hold the thread until there is content in the queue (avoids absurd CPU usage)
             //TODO[playback]: Find a better way to do this (I'm still not sure if blocking the miniaudio data callback is a good idea)
             while queue.is_empty(){
+                //Let the device stop instead of waiting for packets that will never come
+                if !running_clone.load(Ordering::Relaxed){
+                    return;
+                }
                 queue = cvar.wait(queue).unwrap();
             }
 
@@ -64,7 +75,10 @@ impl AudioPlayback {
             if queue.len() > 1 {
                 //Decode opus packet
                 let payload = queue.remove(0);
-                let _ = decoder.decode(&payload.as_slice()[..payload.len()-1], &mut decoded, false).unwrap();
+                //A packet the decoder can't read is skipped
+                if decoder.decode(&payload.as_slice()[..payload.len()-1], &mut decoded, false).is_err(){
+                    return;
+                }
                 //Apply volume by scaling the decoded samples
                 let volume = payload[payload.len()-1] as f32 / 100.0;
                 decoded.iter_mut().for_each(|x| *x = (*x as f32 * volume)as i16);
@@ -74,12 +88,22 @@ impl AudioPlayback {
                 //output.as_bytes_mut().copy_from_slice(&decoded[..len]);
             }
         });
-        AudioPlayback { playback_arc,  playback_device }
+        Ok(AudioPlayback { playback_arc,  playback_device, running })
     }
 
     /// Starts the playback device
-    pub fn start(&self){
-        self.playback_device.start().unwrap();
+    /// # Errors
+    /// * `AudioError::Device` - If the device can't be started
+    pub fn start(&self) -> Result<(), AudioError>{
+        self.playback_device.start().map_err(AudioError::Device)
+    }
+
+    /// Stops the playback device, the device is released once the AudioPlayback is dropped
+    pub fn stop(&self){
+        self.running.store(false, Ordering::Relaxed);
+        //Wake up the data callback in case it is waiting for packets
+        self.playback_arc.1.notify_all();
+        let _ = self.playback_device.stop();
     }
 
     /// Returns a clone of the playback queue
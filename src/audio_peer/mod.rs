@@ -1,155 +1,414 @@
 // SPDX-FileCopyrightText: Copyright 2023 Savi
 // SPDX-License-Identifier: GPL-3.0-only 
 
-use log::debug;
-use tokio::runtime::Runtime;
+use log::{debug, error, info, warn};
+use tokio::runtime::Builder;
 use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use std::{sync::{Arc, Mutex, atomic::{AtomicBool, Ordering, AtomicU64}}, thread};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use miniaudio::DeviceConfig;
-use crate::audio::{playback::AudioPlayback, Audio};
+use crate::aes::AES;
+use crate::audio::{playback::AudioPlayback, Audio, AudioError};
+use crate::signaling::discovery::{Binding, Candidate, CandidateKind};
 use std::cmp::Reverse;
 use std::collections::BinaryHeap;
 
+/// First byte of every datagram, tells the voice apart from the connectivity checks
+const VOICE: u8 = 0;
+const CHECK: u8 = 1;
+const CHECK_REPLY: u8 = 2;
+/// Time between two rounds of connectivity checks
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+/// Time the checks go on after the first pair works, in case a better one works too
+pub const NOMINATION_DELAY: Duration = Duration::from_millis(250);
+/// Type byte and packet number of a voice datagram, sent in the clear but authenticated
+const VOICE_HEADER: usize = 9;
+/// Size of the authentication tag after the encrypted opus packet
+const TAG_SIZE: usize = 16;
+/// Packets this far behind the newest one are dropped, they would be played too late anyway
+const REPLAY_WINDOW: u64 = 64;
+
+/// A local socket and a remote candidate the connectivity checks go through
+struct CandidatePair {
+    socket: usize,
+    remote: SocketAddr,
+    priority: u64,
+    //Echoed by the remote peer, so the answers of other hosts are ignored
+    token: u64,
+    working: bool,
+}
+
+/// Remembers the recent packet numbers of a peer, so a packet recorded and sent
+/// again is dropped
+#[derive(Default)]
+struct ReplayWindow {
+    //Highest packet number accepted, none before the first packet
+    newest: Option<u64>,
+    //Bit n is set if the packet newest - n was accepted
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// ```true``` if the packet number was never accepted and it is recent enough
+    fn is_fresh(&self, number: u64) -> bool {
+        match self.newest {
+            Some(newest) if number <= newest => {
+                let age = newest - number;
+                age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+            }
+            _ => true,
+        }
+    }
+
+    /// Remembers a packet number, only once the packet is authenticated so forged
+    /// packets can't move the window
+    fn accept(&mut self, number: u64) {
+        match self.newest {
+            Some(newest) if number <= newest => self.seen |= 1 << (newest - number),
+            Some(newest) => {
+                let shift = number - newest;
+                self.seen = if shift >= REPLAY_WINDOW { 1 } else { (self.seen << shift) | 1 };
+                self.newest = Some(number);
+            }
+            None => {
+                self.seen = 1;
+                self.newest = Some(number);
+            }
+        }
+    }
+}
+
+/// Serializes and encrypts a voice packet.
+/// The packet is serialized as follows:
+/// <0 1 byte><packet number 8 bytes><encrypted opus packet variable size><tag 16 bytes>
+/// # Arguments
+/// * `sending` - The key we encrypt our voice with
+/// * `number` - The packet number, it is the nonce so it must never repeat
+/// * `opus` - An opus packet
+fn seal_voice(sending: &AES, number: u64, opus: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![VOICE];
+    datagram.extend_from_slice(&number.to_be_bytes());
+    //The header is authenticated along with the opus packet
+    let ciphertext = sending.encrypt_counter(number, &datagram, opus);
+    datagram.extend(ciphertext);
+    datagram
+}
+
+/// Authenticates and decrypts a voice packet made by ```seal_voice```
+/// # Arguments
+/// * `receiving` - The key the remote peer encrypts its voice with
+/// * `replay` - The packet numbers already played, updated if the packet is accepted
+/// * `datagram` - The whole packet
+/// # Returns
+/// * `Some((u64, Vec<u8>))` - The packet number and the opus packet
+/// * `None` - If the packet is too short, a replay, too old or it fails the authentication
+fn open_voice(receiving: &AES, replay: &mut ReplayWindow, datagram: &[u8]) -> Option<(u64, Vec<u8>)> {
+    if datagram.len() <= VOICE_HEADER + TAG_SIZE || datagram[0] != VOICE {
+        return None;
+    }
+    let number = u64::from_be_bytes(datagram[1..VOICE_HEADER].try_into().unwrap());
+    if !replay.is_fresh(number) {
+        debug!("Dropping voice packet {}, it was already played or it is too old", number);
+        return None;
+    }
+    let (header, ciphertext) = datagram.split_at(VOICE_HEADER);
+    match receiving.decrypt_counter(number, header, ciphertext) {
+        Ok(opus) => {
+            replay.accept(number);
+            Some((number, opus))
+        }
+        Err(e) => {
+            debug!("Dropping voice packet {}: {}", number, e);
+            None
+        }
+    }
+}
+
 /// AudioPeer allows for sending and receiving audio packets between two peers
 /// ## Example with ```audio::playback``` and ```audio::capture```
 /// ```no_run
-/// //This program takes 5 arguments: uid bind connect mic_id speaker_id
-/// //Takes the capture queue elements and send it through the socket
-/// //while AudioPeer handles packets and playback
+/// //This program takes 6 arguments: remote_address mic_index speaker_index backend room_key initiator
+/// //Takes the opus packets of the capture and sends them through the socket
+/// //while AudioPeer handles the incoming packets and the playback
 /// 
 /// use std::env;
+/// use std::sync::mpsc;
 /// 
-/// mod audio;
-/// mod audio_peer;
+/// use savi::aes::AES;
+/// use savi::audio::{Audio, AudioError};
+/// use savi::audio::capture::AudioCapture;
+/// use savi::audio::playback::AudioPlayback;
+/// use savi::audio_peer::AudioPeer;
+/// use savi::signaling::discovery::{Binding, Candidate, CandidateKind};
 /// 
-/// use audio::playback::AudioPlayback;
-/// use audio::capture::AudioCapture;
-/// use audio_peer::AudioPeer;
+/// fn main() -> Result<(), AudioError> {
+///     let args: Vec<String> = env::args().skip(1).collect();
+///     if args.len() != 6 {
+///         panic!("invalid number of arguments");
+///     }
+///     Audio::print_devices()?;
 /// 
-/// fn main(){
-/// // args: uid bind connect mic_id speaker_id
-/// let arg: Vec<String> = env::args().collect();
-/// let args = arg[1..].to_vec();
-/// //print devices
-/// audio::Audio::print_devices();
-/// println!("{:?}", args);
-/// if args.len() != 5 {
-///    panic!("invalid number of arguments");
-/// }
+///     let backend = Audio::backend_from_text(args[3].clone());
+///     let inputs = Audio::get_input_devices(Some(backend))?;
+///     let outputs = Audio::get_output_devices(Some(backend))?;
+///     let (tx, rx) = mpsc::channel();
+///     let audio_capture = AudioCapture::new(backend, inputs[args[1].parse::<usize>().unwrap()].1.clone(), 1, 48_000, 96_000, 100, tx)?;
+///     audio_capture.start()?;
 /// 
-/// let inputs = audio::Audio::get_input_devices();
-/// let outputs = audio::Audio::get_output_devices();
-/// let audio_capture = AudioCapture::new(inputs[args[3].parse::<usize>().unwrap()].clone().1,
-/// 1, 48_000, 128_000, 100.0);
-/// audio_capture.start();
+///     //Both ends derive the same keys from the room key, each one sends with the other's receiving key
+///     let room = AES::new(Some(args[4].clone())).expect("invalid room key");
+///     let initiator = args[5] == "true";
+///     let (alice, bob) = (room.derive(b"alice"), room.derive(b"bob"));
+///     let keys = if initiator { (alice, bob) } else { (bob, alice) };
 /// 
-/// let playback_config = AudioPlayback::create_config(outputs[args[4].parse::<usize>().unwrap()].clone().1,2,48_000);
-/// let mut peer = AudioPeer::new(args[0].parse::<u8>().unwrap(),args[1].clone());
-/// peer.connect(args[2].clone(), playback_config);
-/// let capture_arc = audio_capture.get_capture_arc();
+///     let playback_config = AudioPlayback::create_config(outputs[args[2].parse::<usize>().unwrap()].1.clone(), 2, 48_000);
+///     let peer = AudioPeer::new(&Binding::default())?;
+///     let remote = Candidate::new(CandidateKind::Host, Some(args[0].parse().unwrap()));
+///     peer.connect(Vec::new(), vec![remote], initiator, keys, args[3].clone(), playback_config)?;
 /// 
-/// loop{
-///     let (mutex, cvar) = &*capture_arc;
-///     let mut queue = mutex.lock().unwrap();
-///     while queue.is_empty() {
-///         queue = cvar.wait(queue).unwrap();
-///     }
-///     let (packet_number, payload) = queue.pop_front().unwrap();
-///         peer.send(payload);
+///     for packet in rx {
+///         //Dropped until the connectivity checks pick a pair
+///         let _ = peer.send(packet);
 ///     }
+///     Ok(())
 /// }
 /// ```
 pub struct AudioPeer {
     ready: Arc<AtomicBool>,
     packet_count: Arc<AtomicU64>,
     volume: Arc<Mutex<u8>>,
-    udpsocket: Arc<Mutex<std::net::UdpSocket>>,
+    muted: Arc<AtomicBool>,
+    //The voice goes through the signaling host instead of the socket
+    relayed: Arc<AtomicBool>,
+    //Voice packets that came through the signaling host
+    relay_tx: UnboundedSender<Vec<u8>>,
+    relay_rx: Mutex<Option<UnboundedReceiver<Vec<u8>>>>,
+    //One socket per address family, or a single one bound to an interface
+    udpsockets: Vec<std::net::UdpSocket>,
+    //(socket, remote address) of the pair picked by the connectivity checks
+    selected: Arc<Mutex<Option<(usize, SocketAddr)>>>,
+    //Encrypts our voice, none until the link is negotiated
+    cipher: Mutex<Option<AES>>,
+    stop: Arc<Notify>,
 }
 impl AudioPeer {
-    /// Creates a new AudioPeer
+    /// Creates a new AudioPeer with the sockets of a binding, an IPv4 and an IPv6 one
+    /// unless it is bound to a single interface
     /// # Arguments
-    /// * `bind` - The address to bind to
-    pub fn new(bind: String) -> AudioPeer {
-        AudioPeer {
+    /// * `binding` - Where to bind the sockets
+    /// # Errors
+    /// * `std::io::Error` - If no socket can be bound
+    pub fn new(binding: &Binding) -> io::Result<AudioPeer> {
+        let udpsockets = binding.bind_audio()?;
+        let (relay_tx, relay_rx) = mpsc::unbounded_channel();
+        Ok(AudioPeer {
             packet_count: Arc::new(AtomicU64::new(0)),
             ready: Arc::new(AtomicBool::new(false)),
             volume: Arc::new(Mutex::new(100)),
-            //tk_socketqueue: Arc::new(Mutex::new(BinaryHeap::new())),
-            udpsocket: Arc::new(Mutex::new(
-                std::net::UdpSocket::bind(bind).expect("couldn't bind to address"),
-            )),
+            muted: Arc::new(AtomicBool::new(false)),
+            relayed: Arc::new(AtomicBool::new(false)),
+            relay_tx,
+            relay_rx: Mutex::new(Some(relay_rx)),
+            udpsockets,
+            selected: Arc::new(Mutex::new(None)),
+            cipher: Mutex::new(None),
+            stop: Arc::new(Notify::new()),
+        })
+    }
+
+    /// The sockets the candidates are gathered on
+    pub fn sockets(&self) -> &[std::net::UdpSocket] {
+        &self.udpsockets
+    }
+
+    /// Pairs every socket with the remote candidates of its family, best pair first
+    /// # Arguments
+    /// * `local` - Our candidates, their priority ranks the sockets
+    /// * `remote` - The candidates of the remote peer
+    /// * `initiator` - ```true``` if we sent the ```Announce```
+    fn pairs(&self, local: &[Candidate], remote: &[Candidate], initiator: bool) -> Vec<CandidatePair> {
+        let mut pairs = Vec::new();
+        for (index, socket) in self.udpsockets.iter().enumerate() {
+            let try_bound = socket.local_addr();
+            if try_bound.is_err() {
+                continue;
+            }
+            let bound = try_bound.unwrap();
+            //The host candidate of the socket, the server reflexive ones share it
+            let local_priority = local
+                .iter()
+                .find(|candidate| candidate.kind == CandidateKind::Host && candidate.address.map(|address| address.port()) == Some(bound.port()))
+                .map(|candidate| candidate.priority)
+                .unwrap_or(Candidate::new(CandidateKind::Host, Some(bound)).priority);
+            for candidate in remote {
+                let remote_address = match candidate.address {
+                    Some(address) if address.is_ipv6() == bound.is_ipv6() => address,
+                    _ => continue,
+                };
+                pairs.push(CandidatePair {
+                    socket: index,
+                    remote: remote_address,
+                    priority: Candidate::pair_priority(local_priority, candidate.priority, initiator),
+                    token: rand::random(),
+                    working: false,
+                });
+            }
         }
+        pairs.sort_by_key(|pair| Reverse(pair.priority));
+        pairs
     }
 
     // TODO: change playback_config to an AudioPlayback object
-    /// Connects to a peer
+    /// Runs the connectivity checks with a peer and plays what it sends. The peer is
+    /// ready once the best working pair is picked, until then its voice can still
+    /// come through the signaling host
     /// # Arguments
-    /// * `addr` - The address to connect to
+    /// * `local` - Our candidates
+    /// * `remote` - The candidates of the remote peer
+    /// * `initiator` - ```true``` if we sent the ```Announce```, both peers rank the pairs the same way
+    /// * `keys` - The key we encrypt our voice with and the key the remote peer encrypts its voice with
+    /// * `backend` - The audio backend name
     /// * `playback_config` - The configuration for the playback device
-    pub fn connect(&self, addr: String, backend: String, playback_config: DeviceConfig) {
-        //self.target_username = username;
-        //measure time
-        //let start = std::time::Instant::now();
-        
-        let _ = self.udpsocket.lock().unwrap().connect(&addr).expect("couldn't connect to address");
-        //connect to server
-        let socket_clone = self.udpsocket.lock().unwrap().try_clone().unwrap();
+    /// # Errors
+    /// * `AudioError` - If the playback device can't be started or the sockets can't be shared with the audio thread
+    /// * `AudioError::AlreadyConnected` - If ```connect``` was called already
+    pub fn connect(&self, local: Vec<Candidate>, remote: Vec<Candidate>, initiator: bool, keys: (AES, AES), backend: String, playback_config: DeviceConfig) -> Result<(), AudioError> {
+        let mut pairs = self.pairs(&local, &remote, initiator);
+        if pairs.is_empty() {
+            warn!("No candidate pair to check, the voice can only be relayed");
+        }
 
         let volume = self.volume.clone();
+        let muted = self.muted.clone();
+        let relayed = self.relayed.clone();
+        let selected = self.selected.clone();
         let backend = Audio::backend_from_text(backend);
-        let audio_playback = AudioPlayback::new(backend, playback_config);
+        //A single thread is enough for the sockets of one link
+        let rt = Builder::new_current_thread().enable_all().build()?;
+        let mut sockets = Vec::new();
+        {
+            //The sockets are registered with the runtime that waits on them
+            let _runtime = rt.enter();
+            for socket in &self.udpsockets {
+                let socket_clone = socket.try_clone()?;
+                //tokio needs the socket in non-blocking mode to wait on it
+                socket_clone.set_nonblocking(true)?;
+                sockets.push(Arc::new(UdpSocket::from_std(socket_clone)?));
+            }
+        }
+        let audio_playback = AudioPlayback::new(backend, playback_config)?;
+        audio_playback.start()?;
         let ready = self.ready.clone();
-        //Avoids a weird bug where the cpu usage grows when one of the two peers never receives a packet
-        self.udpsocket.lock().unwrap().send(&[1]).unwrap();
-        
+        let stop = self.stop.clone();
+        let mut relay_rx = self.relay_rx.lock().unwrap().take().ok_or(AudioError::AlreadyConnected)?;
+        let (sending, receiving) = keys;
+        *self.cipher.lock().unwrap() = Some(sending);
         
         thread::spawn(move || {
-            audio_playback.start();
-            let rt = Runtime::new().unwrap();
             let mut buffer: BinaryHeap<Reverse<(u64, Vec<u8>)>> = BinaryHeap::new();
-            //TODO[LATENCY]: this may or may not be implemented
-            //let mut start = std::time::Instant::now();
+            let mut replay = ReplayWindow::default();
             
             let playback_arc = audio_playback.get_playback_arc();
             
-            rt.block_on(async move {
-                let tk_socket = UdpSocket::from_std(socket_clone).unwrap();
-                let mut data = [0; 2048];
+            rt.block_on(async {
+                //(socket, sender, datagram) of every socket in a single queue
+                let (datagram_tx, mut datagram_rx) = mpsc::unbounded_channel();
+                for (index, socket) in sockets.iter().enumerate() {
+                    let socket = socket.clone();
+                    let datagram_tx = datagram_tx.clone();
+                    tokio::spawn(async move {
+                        let mut data = [0; 2048];
+                        loop {
+                            match socket.recv_from(&mut data[..]).await {
+                                Ok((n, from)) => {
+                                    if datagram_tx.send((index, from, data[..n].to_vec())).is_err() {
+                                        break;
+                                    }
+                                }
+                                // The other peer is not listening yet, continue
+                                Err(ref e) if e.kind() == tokio::io::ErrorKind::ConnectionRefused => continue,
+                                Err(e) => {
+                                    error!("Audio socket failed: {:?}", e.kind());
+                                    break;
+                                }
+                            }
+                        }
+                    });
+                }
+                drop(datagram_tx);
+
+                let mut checks = tokio::time::interval(CHECK_INTERVAL);
+                //When the first pair worked
+                let mut first_working: Option<Instant> = None;
                 loop {
-                    match tk_socket.try_recv(&mut data[..]) {
-                        Ok(n) => {
-                            if n == 1 && !ready.load(Ordering::Relaxed){
-                                /* TODO[LATENCY]: this may or may not be implemented
-                                let _ = tk_socket.send(&[1]).await;
-                                let elapsed = start.elapsed();
-                                println!("Latency: {}ms", elapsed.as_millis());
-                                start = std::time::Instant::now();
-                                */
-                                debug!("Ready");
-                                ready.store(true, Ordering::Relaxed);
-                                tk_socket.send(&[1]).await.unwrap();
-                                continue;
+                    let received = tokio::select! {
+                        Some((index, from, data)) = datagram_rx.recv() => (Some((index, from)), data),
+                        //The sender lives as long as the audio peer
+                        Some(packet) = relay_rx.recv() => (None, packet),
+                        _ = checks.tick(), if !ready.load(Ordering::Relaxed) && !relayed.load(Ordering::Relaxed) && !pairs.is_empty() => {
+                            if let Some(first) = first_working {
+                                if first.elapsed() >= NOMINATION_DELAY {
+                                    //The pairs are sorted, the first working one is the best
+                                    let best = pairs.iter().find(|pair| pair.working).unwrap();
+                                    info!("Audio goes directly to {}", best.remote);
+                                    *selected.lock().unwrap() = Some((best.socket, best.remote));
+                                    ready.store(true, Ordering::Relaxed);
+                                    continue;
+                                }
                             }
-                            if n < 8 {
-                                continue;
+                            for pair in pairs.iter() {
+                                let mut check = vec![CHECK];
+                                check.extend_from_slice(&pair.token.to_be_bytes());
+                                if let Err(e) = sockets[pair.socket].send_to(&check, pair.remote).await {
+                                    debug!("Connectivity check to {} failed: {}", pair.remote, e);
+                                }
                             }
-                            //Deserialize packet count
-                            let received = data[..n].to_vec();
-                            let recv_packet_count: u64 = bincode::deserialize(&received[n - 8..]).unwrap();
-                            //Push to playback queue
-                            let mut opus = data[..n - 8].to_vec();
-                            opus.push(*volume.lock().unwrap());
-                            let voice = (recv_packet_count, opus);
-                            buffer.push(Reverse(voice));
+                            continue;
                         }
-                        // False-positive, continue
-                        Err(ref e) if e.kind() == tokio::io::ErrorKind::WouldBlock || e.kind() == tokio::io::ErrorKind::ConnectionRefused => {
-                            println!("false positive");
+                        _ = stop.notified() => break,
+                    };
+                    let (origin, received) = received;
+                    let n = received.len();
+                    if n == 0 {
+                        continue;
+                    }
+                    match (received[0], origin) {
+                        (CHECK, Some((index, from))) if n == 9 => {
+                            //Answered even after picking a pair, the remote peer may still be checking
+                            let mut reply = received;
+                            reply[0] = CHECK_REPLY;
+                            let _ = sockets[index].send_to(&reply, from).await;
+                            continue;
                         }
-                        Err(e) => {
-                            panic!("{:?}",e.kind());
+                        (CHECK_REPLY, Some((index, _))) if n == 9 => {
+                            let token = u64::from_be_bytes(received[1..].try_into().unwrap());
+                            if let Some(pair) = pairs.iter_mut().find(|pair| pair.socket == index && pair.token == token) {
+                                if !pair.working {
+                                    debug!("Candidate pair to {} works", pair.remote);
+                                    pair.working = true;
+                                    first_working.get_or_insert(Instant::now());
+                                }
+                            }
+                            continue;
                         }
+                        (VOICE, _) => {}
+                        _ => continue,
+                    }
+                    if muted.load(Ordering::Relaxed) {
+                        continue;
                     }
+                    let (recv_packet_count, mut opus) = match open_voice(&receiving, &mut replay, &received) {
+                        Some(voice) => voice,
+                        None => continue,
+                    };
+                    //Push to playback queue
+                    opus.push(*volume.lock().unwrap());
+                    let voice = (recv_packet_count, opus);
+                    buffer.push(Reverse(voice));
                     //the "is this a jitter buffer¿" implementation
                     if buffer.len() > 1 {
                         let (mutex, cvar) = &*playback_arc;
@@ -162,12 +421,15 @@ impl AudioPeer {
                     }
                 }
             });
+            audio_playback.stop();
+            debug!("Audio peer stopped");
         });
+        Ok(())
     }
 
-    /// Sends a voice packet through the socket.
+    /// Sends a voice packet through the picked candidate pair.
     /// The packet is serialized as follows:
-    /// <opus packet variable size><packet number 8 bytes>
+    /// <0 1 byte><packet number 8 bytes><encrypted opus packet variable size><tag 16 bytes>
     /// # Arguments
     /// * `data` - An opus packet
     /// # Returns
@@ -178,22 +440,164 @@ impl AudioPeer {
         if !self.is_ready(){
             return Err(std::io::Error::new(std::io::ErrorKind::Other, "Peer not ready"));
         }
+        let payload = self.packet(data).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "Peer not ready"))?;
+        let (socket, remote) = self.selected.lock().unwrap().unwrap();
+        self.udpsockets[socket].send_to(&payload, remote)
+    }
+
+    /// Serializes and encrypts a voice packet the same way ```send``` does, for the packets
+    /// that go through the signaling host
+    /// # Arguments
+    /// * `data` - An opus packet
+    /// # Returns
+    /// * `Some(Vec<u8>)` - The datagram
+    /// * `None` - If the link isn't negotiated yet, there is no key to encrypt with
+    pub fn packet(&self, data: Vec<u8>) -> Option<Vec<u8>> {
+        let cipher = self.cipher.lock().unwrap();
+        let cipher = cipher.as_ref()?;
         let packet_count = self.packet_count.fetch_add(1, Ordering::Relaxed);
-        let mut serialized = bincode::serialize(&packet_count).unwrap();
-        
-        let mut payload = data;
-        payload.append(serialized.as_mut());
-        self.udpsocket
-            .lock()
-            .unwrap()
-            .send(&payload)
+        Some(seal_voice(cipher, packet_count, &data))
+    }
+
+    /// Plays a voice packet that came through the signaling host
+    /// # Arguments
+    /// * `packet` - A packet made by ```packet```
+    pub fn receive(&self, packet: Vec<u8>) {
+        let _ = self.relay_tx.send(packet);
+    }
+
+    /// Sends the voice through the signaling host from now on, for the peers we can't reach directly
+    pub fn set_relayed(&self, relayed: bool) {
+        self.relayed.store(relayed, Ordering::Relaxed);
+    }
+
+    pub fn is_relayed(&self) -> bool {
+        self.relayed.load(Ordering::Relaxed)
     }
 
     pub fn change_volume(&self, volume: u8) {
         *self.volume.lock().unwrap() = volume;
     }
     
+    /// Drops the incoming voice packets instead of playing them
+    /// # Arguments
+    /// * `muted` - ```true``` to stop playing the peer, ```false``` to play it again
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
     pub fn is_ready(&self) -> bool {
         self.ready.load(Ordering::Relaxed)
     }
+
+    /// Stops receiving packets and releases the playback device
+    pub fn stop(&self) {
+        self.ready.store(false, Ordering::Relaxed);
+        self.stop.notify_one();
+    }
+}
+
+impl Drop for AudioPeer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A window that accepted these packet numbers, in order
+    fn accepted<I: IntoIterator<Item = u64>>(numbers: I) -> ReplayWindow {
+        let mut window = ReplayWindow::default();
+        for number in numbers {
+            window.accept(number);
+        }
+        window
+    }
+
+    /// A key, an empty window and the opus packet ```opus``` sealed as packet 7
+    fn sealed() -> (AES, ReplayWindow, Vec<u8>) {
+        let key = AES::new(None).unwrap();
+        let datagram = seal_voice(&key, 7, b"opus");
+        (key, ReplayWindow::default(), datagram)
+    }
+
+    #[test]
+    fn accepts_packets_in_order() {
+        let mut window = ReplayWindow::default();
+        for number in 0..200 {
+            assert!(window.is_fresh(number));
+            window.accept(number);
+        }
+        assert_eq!(window.newest, Some(199));
+    }
+
+    #[test]
+    fn rejects_a_duplicate() {
+        assert!(!accepted([5]).is_fresh(5));
+        let window = accepted([5, 6]);
+        assert!(!window.is_fresh(5));
+        assert!(!window.is_fresh(6));
+    }
+
+    #[test]
+    fn accepts_a_late_packet_once_inside_the_window() {
+        let oldest = 102 - (REPLAY_WINDOW - 1);
+        let window = accepted([100, 102]);
+        assert!(window.is_fresh(101));
+        assert!(window.is_fresh(oldest));
+
+        let window = accepted([100, 102, 101, oldest]);
+        assert!(!window.is_fresh(101));
+        assert!(!window.is_fresh(oldest));
+    }
+
+    #[test]
+    fn rejects_a_packet_older_than_the_window() {
+        let window = accepted([1000]);
+        assert!(!window.is_fresh(1000 - REPLAY_WINDOW));
+        assert!(!window.is_fresh(0));
+        assert!(window.is_fresh(1000 - REPLAY_WINDOW + 1));
+    }
+
+    #[test]
+    fn slides_on_a_large_jump() {
+        let window = accepted((0..10).chain([1_000_000]));
+        assert!(!window.is_fresh(1_000_000));
+        assert!(!window.is_fresh(9));
+        assert!(window.is_fresh(999_999));
+        assert!(window.is_fresh(1_000_000 - (REPLAY_WINDOW - 1)));
+
+        let window = accepted([1_000_000, u64::MAX]);
+        assert!(!window.is_fresh(u64::MAX));
+        assert!(!window.is_fresh(1_000_000));
+        assert!(window.is_fresh(u64::MAX - 1));
+    }
+
+    #[test]
+    fn opens_a_sealed_packet_once() {
+        let (key, mut window, datagram) = sealed();
+
+        assert_eq!(open_voice(&key, &mut window, &datagram), Some((7, b"opus".to_vec())));
+        assert_eq!(open_voice(&key, &mut window, &datagram), None);
+    }
+
+    #[test]
+    fn rejects_a_tampered_header() {
+        let (key, mut window, datagram) = sealed();
+
+        //Another packet number, the nonce and the authenticated header no longer match
+        let mut renumbered = datagram.clone();
+        renumbered[VOICE_HEADER - 1] ^= 1;
+        assert_eq!(open_voice(&key, &mut window, &renumbered), None);
+        let mut tampered_tag = datagram.clone();
+        *tampered_tag.last_mut().unwrap() ^= 1;
+        assert_eq!(open_voice(&key, &mut window, &tampered_tag), None);
+        assert_eq!(open_voice(&AES::new(None).unwrap(), &mut window, &datagram), None);
+
+        //Forged packets don't move the window
+        assert_eq!(window.newest, None);
+        assert_eq!(open_voice(&key, &mut window, &datagram), Some((7, b"opus".to_vec())));
+    }
 }
@@ -0,0 +1,215 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Headless signaling server. It hosts rooms for the Savi clients without
+//! opening any audio device or taking part in the calls
+
+#[macro_use]
+extern crate log;
+
+use std::fs;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+use savi::signaling::heartbeat::Heartbeat;
+use savi::signaling::server::{SignalingServer, DEFAULT_ROOM};
+
+const DEFAULT_BIND: &str = "::";
+const DEFAULT_PORT: u16 = 7700;
+
+const USAGE: &str = "Usage: savi-signal [options]
+
+Options:
+    -b, --bind <address>     Address to listen on (default ::)
+    -p, --port <port>        Port to listen on (default 7700)
+    -w, --websocket <port>   Also accept WebSocket connections on this port, put a
+                             TLS reverse proxy in front of it for wss://
+    -r, --room <name[=key]>  Room to host, can be repeated (default lobby)
+    -k, --key <key>          Key of the rooms given without one, random if not set
+        --ping-interval <s>  Seconds between two pings to every peer (default 5)
+        --ping-timeout <s>   Seconds of silence before a peer is dropped (default 15)
+    -c, --config <file>      Read the options from a file, one 'option = value' per line
+    -h, --help               Show this message
+
+Log verbosity is set with the RUST_LOG environment variable (default info)";
+
+/// Server options, the command line ones override the config file ones
+struct Config {
+    bind: String,
+    port: u16,
+    websocket: Option<u16>,
+    //(name, key)
+    rooms: Vec<(String, Option<String>)>,
+    key: Option<String>,
+    heartbeat: Heartbeat,
+}
+
+impl Config {
+    fn new() -> Self {
+        Config {
+            bind: DEFAULT_BIND.to_string(),
+            port: DEFAULT_PORT,
+            websocket: None,
+            rooms: Vec::new(),
+            key: None,
+            heartbeat: Heartbeat::default(),
+        }
+    }
+
+    /// Sets an option by its long name
+    /// # Arguments
+    /// * `option` - The option name without dashes
+    /// * `value` - The option value
+    fn set(&mut self, option: &str, value: String) -> Result<(), String> {
+        match option {
+            "bind" => self.bind = value,
+            "port" => self.port = value.parse().map_err(|_| format!("invalid port {}", value))?,
+            "websocket" => self.websocket = Some(value.parse().map_err(|_| format!("invalid port {}", value))?),
+            "key" => self.key = Some(value),
+            "ping-interval" => self.heartbeat.interval = parse_seconds(&value)?,
+            "ping-timeout" => self.heartbeat.timeout = parse_seconds(&value)?,
+            "room" => {
+                let room = match value.split_once('=') {
+                    Some((name, key)) => (name.trim().to_string(), Some(key.trim().to_string())),
+                    None => (value, None),
+                };
+                if room.0.is_empty() {
+                    return Err("empty room name".to_string());
+                }
+                self.rooms.push(room);
+            }
+            _ => return Err(format!("unknown option {}", option)),
+        }
+        Ok(())
+    }
+
+    /// Reads a config file, empty lines and lines starting with # are ignored
+    /// # Arguments
+    /// * `path` - The config file
+    fn load(&mut self, path: &str) -> Result<(), String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let try_option = line.split_once('=');
+            if try_option.is_none() {
+                return Err(format!("{}:{}: expected 'option = value'", path, number + 1));
+            }
+            let (option, value) = try_option.unwrap();
+            self.set(option.trim(), value.trim().to_string())
+                .map_err(|e| format!("{}:{}: {}", path, number + 1, e))?;
+        }
+        Ok(())
+    }
+
+    /// Builds the config from the command line arguments
+    /// # Arguments
+    /// * `args` - The arguments without the program name
+    fn from_args(args: Vec<String>) -> Result<Self, String> {
+        let mut options = Vec::new();
+        let mut iter = args.into_iter();
+        while let Some(arg) = iter.next() {
+            let option = match arg.as_str() {
+                "-b" | "--bind" => "bind",
+                "-p" | "--port" => "port",
+                "-w" | "--websocket" => "websocket",
+                "-r" | "--room" => "room",
+                "-k" | "--key" => "key",
+                "-c" | "--config" => "config",
+                "--ping-interval" => "ping-interval",
+                "--ping-timeout" => "ping-timeout",
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                _ => return Err(format!("unknown argument {}", arg)),
+            };
+            let value = iter.next().ok_or(format!("missing value for {}", arg))?;
+            options.push((option, value));
+        }
+
+        let mut config = Config::new();
+        //The config file goes first so the command line can override it
+        for (_, path) in options.iter().filter(|(option, _)| *option == "config") {
+            config.load(path)?;
+        }
+        for (option, value) in options.into_iter().filter(|(option, _)| *option != "config") {
+            config.set(option, value)?;
+        }
+        if config.heartbeat.timeout <= config.heartbeat.interval {
+            return Err("the ping timeout has to be longer than the ping interval".to_string());
+        }
+        if config.rooms.is_empty() {
+            config.rooms.push((DEFAULT_ROOM.to_string(), None));
+        }
+        Ok(config)
+    }
+}
+
+/// Parses a positive number of seconds
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 && seconds.is_finite() => Ok(Duration::from_secs_f64(seconds)),
+        _ => Err(format!("invalid number of seconds {}", value)),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let try_config = Config::from_args(std::env::args().skip(1).collect());
+    if let Err(e) = &try_config {
+        eprintln!("savi-signal: {}\n\n{}", e, USAGE);
+        process::exit(2);
+    }
+    let config = try_config.unwrap();
+
+    let try_server = SignalingServer::headless((config.bind.as_str(), config.port));
+    if let Err(e) = &try_server {
+        error!("Failed to listen on {} port {}: {}", config.bind, config.port, e);
+        process::exit(1);
+    }
+    let mut server = try_server.unwrap().with_heartbeat(config.heartbeat);
+    if let Some(port) = config.websocket {
+        match server.with_websocket((config.bind.as_str(), port)) {
+            Ok(websocket) => server = websocket,
+            Err(e) => {
+                error!("Failed to listen for WebSocket connections on {} port {}: {}", config.bind, port, e);
+                process::exit(1);
+            }
+        }
+    }
+    let server = Arc::new(server);
+    info!("Listening on {}", server.get_listen_address());
+    if let Some(websocket) = server.get_websocket_address() {
+        info!("Listening for WebSocket connections on {}", websocket);
+    }
+
+    for (name, key) in config.rooms {
+        let key = key.or(config.key.clone());
+        match server.create_room(name.clone(), key) {
+            Some(key) => info!("Room {} key: {}", name, key),
+            None => {
+                error!("Can't host room {}, the name is taken or the key is not valid", name);
+                process::exit(1);
+            }
+        }
+    }
+
+    tokio::select! {
+        served = server.clone().serve() => {
+            if let Err(e) = served {
+                error!("The server stopped: {}", e);
+                process::exit(1);
+            }
+        }
+        _ = tokio::signal::ctrl_c() => {
+            info!("Shutting down");
+            server.hang_up();
+        }
+    }
+}
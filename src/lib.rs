@@ -0,0 +1,12 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only 
+
+#[macro_use]
+extern crate log;
+
+pub mod aes;
+#[cfg(feature = "audio")]
+pub mod audio;
+#[cfg(feature = "audio")]
+pub mod audio_peer;
+pub mod signaling;
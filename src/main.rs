@@ -8,6 +8,7 @@ use miniaudio::{Context, Backend};
 use slint::{Model, SharedString};
 use std::fmt::format;
 use std::net::UdpSocket;
+use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, AtomicI32};
 use std::sync::{Arc, Mutex, mpsc};
@@ -17,16 +18,20 @@ use std::thread;
 use tokio::runtime::Runtime;
 slint::include_modules!();
 
-mod aes;
-mod signaling;
-mod audio;
+use savi::{audio, audio_peer, signaling};
 use audio::playback::AudioPlayback;
 use audio::capture::AudioCapture;
-use audio::Audio;
-mod audio_peer;
+use audio::{Audio, AudioError};
 use audio_peer::AudioPeer;
 use signaling::server::SignalingServer;
 use signaling::client::SignalingClient;
+use signaling::PeerId;
+use signaling::chat::ChatEntry;
+use signaling::message::Presence;
+use signaling::invite::Invite;
+use signaling::discovery::{self, AddressResolver, Binding, Discovery};
+use signaling::lan::{self, LanAnnouncer, LanBrowser, RoomAnnouncement};
+use signaling::protocol::PROTOCOL_VERSION;
 
 use crate::audio::capture;
 
@@ -44,6 +49,7 @@ impl PeerListData {
         self.data.push(Peer {
             id: id,
             name: name,
+            ..Default::default()
         })
     }
     pub fn set_data(&mut self, peers: Vec<Peer>) {
@@ -54,14 +60,309 @@ impl PeerListData {
         self.data.clone()
     }
 
-    pub fn create_peer(id: u8, name: String) -> Peer {
+    pub fn create_peer(id: PeerId, name: String, presence: Presence, relayed: bool) -> Peer {
         Peer {
             id: id as i32,
             name: name.into(),
+            muted: presence.muted,
+            deafened: presence.deafened,
+            away: presence.away,
+            speaking: presence.speaking,
+            relayed: relayed,
         }
     }
+
+    /// Keeps the PeerList of the gui in sync with the peers in the call
+    /// # Arguments
+    /// * `app_weak` - The app to update
+    /// * `get_peers` - Returns the current peers, their presence and whether they are relayed, sorted by id
+    pub fn watch<F>(app_weak: slint::Weak<App>, get_peers: F)
+    where F: Fn() -> Vec<(PeerId, String, Presence, bool)> + Send + 'static {
+        thread::spawn(move ||{
+            let mut last_peers = Vec::new();
+            loop{
+                let app = app_weak.clone();
+                let peers = get_peers();
+                //Compare the whole list, a leave and a join can happen between two checks
+                if peers != last_peers{
+                    debug!("Peers: {:#?}", peers);
+                    last_peers = peers.clone();
+                    let res = slint::invoke_from_event_loop(move ||{
+                        let mut peer_data = PeerListData::new();
+                        let mut peers_vec = Vec::new();
+                        for peer in peers.iter() {
+                            let data = peer.clone();
+                            let peer_slint = PeerListData::create_peer(data.0, data.1, data.2, data.3);
+                            peers_vec.push(peer_slint);
+                        }
+                        let mut recipients = vec![SharedString::from("Everyone")];
+                        recipients.extend(peers_vec.iter().map(|peer| peer.name.clone()));
+                        peer_data.set_data(peers_vec);
+                        let app = app.unwrap();
+                        app.global::<PeerList>().set_peers(peer_data.get_data().clone().into());
+                        let chat = app.global::<Chat>();
+                        chat.set_recipients(Rc::new(slint::VecModel::from(recipients)).into());
+                        //Keep the chat on the same peer, or back to everyone if it left
+                        match peers.iter().position(|peer| peer.0 as i32 == chat.get_recipient()) {
+                            Some(index) => chat.set_recipient_index(index as i32 + 1),
+                            None => {
+                                chat.set_recipient(-1);
+                                chat.set_recipient_index(0);
+                            }
+                        }
+                    });
+                    if res.is_err(){
+                        error!("Error updating peers: {:?}", res.err().unwrap());
+                    }
+                }
+                thread::sleep(std::time::Duration::from_millis(100));
+            }
+        });
+    }
+}
+
+struct LanRoomData;
+
+impl LanRoomData {
+    /// Keeps the room list of the gui in sync with the rooms announced on the network
+    /// # Arguments
+    /// * `app_weak` - The app to update
+    /// * `browser` - Listens for the announcements
+    /// * `shown` - The rooms in the gui, in the same order, so they can be joined
+    pub fn watch(app_weak: slint::Weak<App>, browser: LanBrowser, shown: Arc<Mutex<Vec<lan::LanRoom>>>) {
+        thread::spawn(move ||{
+            loop{
+                let rooms = browser.rooms();
+                if rooms != *shown.lock().unwrap(){
+                    *shown.lock().unwrap() = rooms.clone();
+                    let app = app_weak.clone();
+                    let res = slint::invoke_from_event_loop(move ||{
+                        let rooms_vec: Vec<LanRoom> = rooms.into_iter().map(|room| LanRoom {
+                            room: room.room.into(),
+                            username: room.username.into(),
+                            address: room.address.into(),
+                            needs_key: room.key.is_none(),
+                        }).collect();
+                        app.unwrap().global::<Lan>().set_rooms(Rc::new(slint::VecModel::from(rooms_vec)).into());
+                    });
+                    if res.is_err(){
+                        error!("Error updating the rooms on the network: {:?}", res.err().unwrap());
+                    }
+                }
+                thread::sleep(std::time::Duration::from_secs(1));
+            }
+        });
+    }
+}
+
+/// Shows in the connection page why the audio link with a peer failed to start
+/// # Arguments
+/// * `app_weak` - The app to update
+/// * `take_error` - Returns the last audio link error, once
+fn watch_audio_errors<F>(app_weak: slint::Weak<App>, take_error: F)
+where F: Fn() -> Option<AudioError> + Send + 'static {
+    thread::spawn(move ||{
+        loop{
+            if let Some(e) = take_error(){
+                let message = format!("No audio with a peer: {}", e);
+                let app = app_weak.clone();
+                let res = slint::invoke_from_event_loop(move ||{
+                    app.unwrap().global::<Signaling>().set_error(message.into());
+                });
+                if res.is_err(){
+                    error!("Error updating the audio error: {:?}", res.err().unwrap());
+                }
+            }
+            thread::sleep(std::time::Duration::from_millis(500));
+        }
+    });
+}
+
+/// Shows in the connection page why we left the room when it wasn't our choice
+/// # Arguments
+/// * `app_weak` - The app to update
+/// * `message` - What went wrong
+fn show_disconnected(app_weak: slint::Weak<App>, message: String) {
+    let res = slint::invoke_from_event_loop(move ||{
+        let app = app_weak.unwrap();
+        app.global::<Signaling>().set_connected(false);
+        app.global::<Signaling>().set_hosting(false);
+        app.global::<Signaling>().set_error(message.into());
+    });
+    if res.is_err(){
+        error!("Error updating the connection status: {:?}", res.err().unwrap());
+    }
+}
+
+/// Shows how a connection attempt made off the gui thread ended
+/// # Arguments
+/// * `app_weak` - The app to update
+/// * `error` - Why the attempt failed, ```None``` if we joined the room
+fn finish_connecting(app_weak: slint::Weak<App>, error: Option<String>) {
+    let res = slint::invoke_from_event_loop(move ||{
+        let app = app_weak.unwrap();
+        app.global::<Signaling>().set_connecting(false);
+        match error {
+            Some(message) => app.global::<Signaling>().set_error(message.into()),
+            None => app.global::<Signaling>().set_connected(true),
+        }
+    });
+    if res.is_err(){
+        error!("Error updating the connection status: {:?}", res.err().unwrap());
+    }
+}
+
+/// Lists the devices of a backend, the error is shown in the settings page if the backend can't be used
+/// # Arguments
+/// * `app` - The app to show the error in
+/// * `devices` - What listing the devices returned
+fn device_list(app: &App, devices: Result<Vec<(String, miniaudio::DeviceId)>, AudioError>) -> Vec<(String, miniaudio::DeviceId)> {
+    match devices {
+        Ok(devices) => devices,
+        Err(e) => {
+            error!("Can't list the audio devices: {}", e);
+            app.global::<AudioDevices>().set_error(format!("Can't list the audio devices: {}", e).into());
+            Vec::new()
+        }
+    }
+}
+
+/// Opens a capture device and starts encoding its samples
+/// # Errors
+/// * `AudioError` - If the device can't be opened or started
+fn start_capture(backend: Backend, device_id: miniaudio::DeviceId, threshold: i32, tx: mpsc::Sender<Vec<u8>>) -> Result<AudioCapture, AudioError> {
+    let capture = AudioCapture::new(backend, device_id, 1, 48_000, 96_000, threshold, tx)?;
+    capture.start()?;
+    Ok(capture)
+}
+
+/// Our mute, deafen and away state as set in the gui
+fn self_presence(app: &App) -> Presence {
+    let self_peer = app.global::<SelfPeer>();
+    Presence {
+        muted: self_peer.get_muted(),
+        deafened: self_peer.get_deafened(),
+        away: self_peer.get_away(),
+        speaking: false,
+    }
+}
+
+const USAGE: &str = "Usage: savi [options]
+
+Options:
+    --bind <address>         Interface to listen on (default every interface)
+    --port <port>            Port to host rooms on (default any free port)
+    --audio-ports <a-b>      Ports the audio may use, for firewall rules (default any)
+    --advertise <address>    Address given to the other peers instead of the discovered one
+    --stun <servers>         STUN servers, host:port separated by commas
+    --lan                    Don't ask any STUN server
+    -h, --help               Show this message
+
+The same settings can be changed in the settings page";
+
+/// Parses an optional address of the network settings, with or without brackets
+fn parse_ip(text: &str, name: &str) -> Result<Option<std::net::IpAddr>, String> {
+    let text = text.trim().trim_start_matches('[').trim_end_matches(']');
+    if text.is_empty() {
+        return Ok(None);
+    }
+    text.parse().map(Some).map_err(|_| format!("invalid {} {}", name, text))
+}
+
+/// Finds our addresses the way the network settings of the gui say
+/// # Errors
+/// * `String` - If a setting is not valid
+fn resolver(app: &App) -> Result<AddressResolver, String> {
+    let network = app.global::<Network>();
+    let servers = Discovery::parse_servers(network.get_stun_servers().as_str());
+    let discovery = if let Some(ip) = parse_ip(network.get_advertise().as_str(), "advertised address")? {
+        Discovery::Advertise { ip }
+    } else if network.get_lan() {
+        Discovery::Lan
+    } else if servers.is_empty() {
+        Discovery::default()
+    } else {
+        Discovery::stun(servers)
+    };
+    let port = network.get_port();
+    let binding = Binding {
+        ip: parse_ip(network.get_bind().as_str(), "bind address")?,
+        port: if port.trim().is_empty() { 0 } else { port.trim().parse().map_err(|_| format!("invalid port {}", port))? },
+        audio_ports: Binding::parse_ports(network.get_audio_ports().as_str())?,
+    };
+    Ok(AddressResolver::new(discovery).with_binding(binding))
+}
+
+/// Fills the network settings in from the command line
+/// # Arguments
+/// * `app` - The app
+/// * `args` - The arguments without the program name
+fn apply_args(app: &App, args: Vec<String>) -> Result<(), String> {
+    let network = app.global::<Network>();
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--lan" => {
+                network.set_lan(true);
+                continue;
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => {}
+        }
+        let value: SharedString = iter.next().ok_or(format!("missing value for {}", arg))?.into();
+        match arg.as_str() {
+            "--bind" => network.set_bind(value),
+            "--port" => network.set_port(value),
+            "--audio-ports" => network.set_audio_ports(value),
+            "--advertise" => network.set_advertise(value),
+            "--stun" => network.set_stun_servers(value),
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    //Reports the invalid values right away instead of on the first call
+    resolver(app).map(|_| ())
 }
 
+struct ChatData;
+
+impl ChatData {
+    pub fn create_line(entry: ChatEntry) -> ChatLine {
+        ChatLine {
+            name: entry.username.into(),
+            text: entry.text.into(),
+            direct: entry.direct,
+        }
+    }
+
+    /// Keeps the chat history of the gui in sync with the one of the room
+    /// # Arguments
+    /// * `app_weak` - The app to update
+    /// * `get_chat` - Returns the current history, oldest first
+    pub fn watch<F>(app_weak: slint::Weak<App>, get_chat: F)
+    where F: Fn() -> Vec<ChatEntry> + Send + 'static {
+        thread::spawn(move ||{
+            let mut last_chat = Vec::new();
+            loop{
+                let app = app_weak.clone();
+                let chat = get_chat();
+                if chat != last_chat{
+                    last_chat = chat.clone();
+                    let res = slint::invoke_from_event_loop(move ||{
+                        let lines: Vec<ChatLine> = chat.into_iter().map(ChatData::create_line).collect();
+                        app.unwrap().global::<Chat>().set_lines(Rc::new(slint::VecModel::from(lines)).into());
+                    });
+                    if res.is_err(){
+                        error!("Error updating chat: {:?}", res.err().unwrap());
+                    }
+                }
+                thread::sleep(std::time::Duration::from_millis(100));
+            }
+        });
+    }
+}
 
 fn main() {
     //
@@ -105,6 +406,8 @@ fn main() {
     let app_weak = app.as_weak();
     let app_weak2 = app.as_weak();
     let app_weak3 = app.as_weak();
+    let app_weak4 = app.as_weak();
+    let app_weak5 = app.as_weak();
 
 
 
@@ -121,8 +424,8 @@ fn main() {
     let backend_arc3 = backend_arc.clone();
     let backend_arc4 = backend_arc.clone();
 
-    let capture_devices = Audio::get_input_devices(Some(default_backend));
-    let playback_devices = Audio::get_output_devices(Some(default_backend));
+    let capture_devices = device_list(&app, Audio::get_input_devices(Some(default_backend)));
+    let playback_devices = device_list(&app, Audio::get_output_devices(Some(default_backend)));
 
     let mut capture_devices_str = Vec::new();
     for device in capture_devices.iter() {
@@ -142,42 +445,63 @@ fn main() {
     app.global::<AudioDevices>().set_capture_backend(backend_list[0].clone());
     app.global::<AudioDevices>().set_playback_backend(backend_list[0].clone());
     app.global::<AudioDevices>().set_backends(cbrc.into());
+    app.global::<Network>().set_stun_servers(discovery::DEFAULT_STUN_SERVERS.join(", ").into());
+    if let Err(e) = apply_args(&app, std::env::args().skip(1).collect()) {
+        eprintln!("savi: {}\n\n{}", e, USAGE);
+        std::process::exit(2);
+    }
 
-    *playback_id_clone.lock().unwrap() = playback_devices[0].0.clone();
+    match playback_devices.first(){
+        Some(device) => *playback_id_clone.lock().unwrap() = device.0.clone(),
+        None => app.global::<AudioDevices>().set_error("No output device found".into()),
+    }
 
     let (capture_tx, capture_rx) = mpsc::channel::<Vec<u8>>();
     let capture_rx_arc = Arc::new(Mutex::new(capture_rx));
     let capture_rx_arc2 = capture_rx_arc.clone();
 
-    let (peercontrol_tx, peercontrol_rx) = mpsc::channel::<(u8,u8)>();
+    let (peercontrol_tx, peercontrol_rx) = mpsc::channel::<(PeerId,u8)>();
     let peercontrol_rx_arc = Arc::new(Mutex::new(peercontrol_rx));
     let peercontrol_rx_arc2 = peercontrol_rx_arc.clone();
 
-    let capture_device: Arc<Mutex<AudioCapture>> = Arc::new(Mutex::new(AudioCapture::new(default_backend, capture_devices[0].1.clone(), 
-    1, 48_000, 96_000, 0, capture_tx.clone())));
-    capture_device.lock().unwrap().start();
-
-    let bind = capture_device.lock().unwrap().get_conn_addr();
-    let connect = capture_device.lock().unwrap().get_queue_addr();
-    info!("Bind: {}", bind);
-    info!("Connect: {}", connect);
+    //None while the input device can't be used
+    let capture_device: Arc<Mutex<Option<AudioCapture>>> = Arc::new(Mutex::new(None));
+    match capture_devices.first(){
+        Some(device) => match start_capture(default_backend, device.1.clone(), 0, capture_tx.clone()){
+            Ok(capture) => *capture_device.lock().unwrap() = Some(capture),
+            Err(e) => {
+                error!("Can't use the input device {}: {}", device.0, e);
+                app.global::<AudioDevices>().set_error(format!("Can't use the input device {}: {}", device.0, e).into());
+            }
+        },
+        None => app.global::<AudioDevices>().set_error("No input device found".into()),
+    }
 
     let capture_device_clone = capture_device.clone();
     let capture_device_clone2 = capture_device.clone();
     let capture_device_clone3 = capture_device.clone();
     let capture_device_clone4 = capture_device.clone();
-    let capture_device_clone5 = capture_device.clone();
     let capture_device_clone6 = capture_device.clone();
 
     app.global::<AudioDevices>().on_set_capture(move |id|{
         let threshold = app_clone.global::<AudioDevices>().get_input_threshold();
         println!("Capture device set to {}", capture_devices_str[id as usize].as_str());
-        capture_device_clone.lock().unwrap().stop();
+        let mut capture = capture_device_clone.lock().unwrap();
+        if let Some(Err(e)) = capture.take().map(|old| old.stop()){
+            warn!("Failed to stop the input device: {}", e);
+        }
         let backend_str = app_clone6.global::<AudioDevices>().get_capture_backend().to_string();
         let backend = Audio::backend_from_text(backend_str);
-        *capture_device_clone.lock().unwrap() = AudioCapture::new(backend, capture_devices[id as usize].1.clone(), 
-        1, 48_000, 96_000, threshold, capture_tx.clone());
-        capture_device_clone.lock().unwrap().start();
+        match start_capture(backend, capture_devices[id as usize].1.clone(), threshold, capture_tx.clone()){
+            Ok(new_capture) => {
+                *capture = Some(new_capture);
+                app_clone.global::<AudioDevices>().set_error(SharedString::default());
+            }
+            Err(e) => {
+                error!("Can't use the input device {}: {}", capture_devices_str[id as usize], e);
+                app_clone.global::<AudioDevices>().set_error(format!("Can't use the input device {}: {}", capture_devices_str[id as usize], e).into());
+            }
+        }
     });
 
     app.global::<AudioDevices>().on_in_settings(move || {
@@ -186,7 +510,7 @@ fn main() {
         thread::spawn(move ||{
             let run:Arc<AtomicBool> = Arc::new(true.into());
             while run.load(std::sync::atomic::Ordering::Relaxed){
-                let th = cc.lock().unwrap().get_intensity();
+                let th = cc.lock().unwrap().as_ref().map(|capture| capture.get_intensity()).unwrap_or(0);
                 //c2.lock().unwrap().global::<AudioDevices>().set_input_intensity(th);
                 c2.upgrade_in_event_loop(move |handle| handle.global::<AudioDevices>().set_input_intensity(th)).unwrap();
                 let x = run.clone();
@@ -198,8 +522,17 @@ fn main() {
     });
 
     app.global::<AudioDevices>().on_set_bitrate(move |bitrate_str|{
-        let bitrate = bitrate_str.parse::<i32>().unwrap();
-        capture_device_clone3.lock().unwrap().set_encoder_bitrate(bitrate);
+        let try_bitrate = bitrate_str.parse::<i32>();
+        if try_bitrate.is_err(){
+            warn!("Invalid bitrate {}", bitrate_str);
+            return;
+        }
+        let bitrate = try_bitrate.unwrap();
+        if let Some(capture) = capture_device_clone3.lock().unwrap().as_ref(){
+            if let Err(e) = capture.set_encoder_bitrate(bitrate){
+                error!("Can't set the bitrate to {}: {}", bitrate, e);
+            }
+        }
     });
 
     app.global::<AudioDevices>().on_set_playback(move |name|{
@@ -210,7 +543,7 @@ fn main() {
     app.global::<AudioDevices>().on_set_capture_backend(move |backend|{
         *backend_arc2.lock().unwrap() = backend.to_string();
         let backend_obj = Audio::backend_from_text(backend.to_string());
-        let capture_devices = Audio::get_input_devices(Some(backend_obj));
+        let capture_devices = device_list(&app_clone4, Audio::get_input_devices(Some(backend_obj)));
 
         let mut capture_devices_str = Vec::new();
         for device in capture_devices.iter() {
@@ -225,7 +558,7 @@ fn main() {
     app.global::<AudioDevices>().on_set_playback_backend(move |backend|{
         *backend_arc3.lock().unwrap() = backend.to_string();
         let backend_obj = Audio::backend_from_text(backend.to_string());
-        let playback_devices = Audio::get_output_devices(Some(backend_obj));
+        let playback_devices = device_list(&app_clone5, Audio::get_output_devices(Some(backend_obj)));
 
         let mut playback_devices_str = Vec::new();
         for device in playback_devices.iter() {
@@ -238,22 +571,105 @@ fn main() {
     });
 
     app.global::<PeerList>().on_change_volume(move |id, volume|{
-        peercontrol_tx.send((id as u8, volume as u8)).unwrap();
+        peercontrol_tx.send((id as PeerId, volume as u8)).unwrap();
     });
 
     //Network
-    let cs_instance: Arc<Mutex<(Option<SignalingClient>,Option<SignalingServer>)>> = Arc::new(Mutex::new((None, None)));
+    let cs_instance: Arc<Mutex<(Option<Arc<SignalingClient>>,Option<Arc<SignalingServer>>)>> = Arc::new(Mutex::new((None, None)));
     let cs_instance_clone = cs_instance.clone();
     let cs_instance_clone2 = cs_instance.clone();
+    let cs_instance_clone3 = cs_instance.clone();
+    let cs_instance_clone4 = cs_instance.clone();
+    let cs_instance_clone5 = cs_instance.clone();
+
+    app.global::<PeerList>().on_drop(move |id|{
+        let ban = app_clone7.global::<PeerList>().get_ban();
+        let instance = cs_instance_clone4.lock().unwrap();
+        //We may have taken over the room after a host migration
+        let dropped = if let Some(server) = instance.1.as_ref(){
+            server.kick(id as PeerId, ban)
+        } else if let Some(client) = instance.0.as_ref(){
+            client.kick(id as PeerId, ban)
+        } else {
+            false
+        };
+        if !dropped{
+            warn!("Couldn't drop peer {}", id);
+        }
+    });
+
+    app.global::<Chat>().on_send(move |target, text|{
+        let target = if target < 0 { None } else { Some(target as PeerId) };
+        let instance = cs_instance_clone3.lock().unwrap();
+        let peers = match (instance.0.as_ref(), instance.1.as_ref()) {
+            (Some(client), _) => client.get_peers(),
+            (None, Some(server)) => server.get_peers(),
+            (None, None) => return,
+        };
+        //The peer may have left since it was picked
+        if let Some(id) = target {
+            if !peers.iter().any(|peer| peer.0 == id) {
+                warn!("Not sending the chat message, peer {} left the call", id);
+                return;
+            }
+        }
+        if let Some(client) = instance.0.as_ref(){
+            client.send_chat(target, text.to_string());
+        } else if let Some(server) = instance.1.as_ref(){
+            server.send_chat(target, text.to_string());
+        }
+    });
+
+    app.global::<SelfPeer>().on_set_presence(move |muted, deafened, away|{
+        let presence = Presence { muted, deafened, away, speaking: false };
+        let instance = cs_instance_clone5.lock().unwrap();
+        if let Some(client) = instance.0.as_ref(){
+            client.set_presence(presence);
+        } else if let Some(server) = instance.1.as_ref(){
+            server.set_presence(presence);
+        }
+    });
 
+    //Announces our room as long as we host it
+    let lan_announcer: RefCell<Option<LanAnnouncer>> = RefCell::new(None);
     app.global::<Signaling>().on_create(move ||{
         let backend = backend_arc.lock().unwrap().clone();
         let username = app_clone2.global::<SelfPeer>().get_name().to_string();
+        let mut room = app_clone2.global::<Signaling>().get_room().to_string();
+        if room.is_empty(){
+            room = signaling::server::DEFAULT_ROOM.to_string();
+        }
         
-        let server = SignalingServer::new(username);
-        let listen = server.get_listen_address();
+        let try_resolver = resolver(&app_clone2);
+        if let Err(e) = &try_resolver {
+            app_clone2.global::<Signaling>().set_error(format!("Invalid network settings: {}", e).into());
+            return;
+        }
+        let try_server = SignalingServer::new(username.clone(), room.clone(), try_resolver.unwrap());
+        if let Err(e) = &try_server {
+            error!("Failed to host room {}: {}", room, e);
+            app_clone2.global::<Signaling>().set_error(format!("Failed to host room {}: {}", room, e).into());
+            return;
+        }
+        app_clone2.global::<Signaling>().set_error(SharedString::default());
+        let server = try_server.unwrap();
+        let listen = server.get_address();
         let key = server.get_cipher_key();
         
+        if app_clone2.global::<Lan>().get_announce(){
+            let share_key = app_clone2.global::<Lan>().get_share_key();
+            let announcement = RoomAnnouncement {
+                protocol: PROTOCOL_VERSION,
+                room: room.clone(),
+                username,
+                port: server.get_listen_port(),
+                key: share_key.then(|| key.clone()),
+            };
+            match LanAnnouncer::start(announcement){
+                Ok(announcer) => *lan_announcer.borrow_mut() = Some(announcer),
+                Err(e) => warn!("Can't announce room {} on the network: {}", room, e),
+            }
+        }
         app_clone2.global::<Signaling>().set_address(slint::SharedString::from(listen));
         app_clone2.global::<Signaling>().set_key(slint::SharedString::from(key));
         app_clone2.global::<Signaling>().set_hosting(true);
@@ -264,17 +680,25 @@ fn main() {
         let peer_rx = peercontrol_rx_arc.clone();
 
         let app_weak = app_weak2.clone();
+        let server_arc = Arc::new(server);
+        server_arc.set_presence(self_presence(&app_clone2));
+        cs_instance_clone.lock().unwrap().1 = Some(server_arc.clone());
         thread::spawn(move ||{
             let app_weak = app_weak.clone();
 
-            let server_arc = Arc::new(server);
             let server_arc2 = server_arc.clone();
             let server_arc3 = server_arc.clone();
             let server_arc4 = server_arc.clone();
+            let server_arc5 = server_arc.clone();
+            let server_arc6 = server_arc.clone();
             let rx2 = rx.clone();
             let peer_rx2 = peer_rx.clone();
+            let app_weak2 = app_weak.clone();
             thread::spawn(move ||{
-                server_arc2.run(backend, playback_name);
+                if let Err(e) = server_arc2.run(backend, playback_name){
+                    error!("Stopped hosting the room: {}", e);
+                    show_disconnected(app_weak2, format!("Stopped hosting the room: {}", e));
+                }
             });
             thread::spawn(move||{
                 let c_rx = rx2.lock().unwrap();
@@ -299,68 +723,143 @@ fn main() {
                     server_arc3.change_peer_volume(id, volume);
                 }
             });
-            thread::spawn(move ||{
-                let app_weak = app_weak.clone();
-                let mut n_peers = 0;
-                loop{
-                    let app = app_weak.clone();
-                    let peers = server_arc4.get_peers();
-                    info!("Peers: {:#?}", peers);
-                    if peers.len() != n_peers{
-                        n_peers = peers.len();
-                        let res = slint::invoke_from_event_loop(move ||{
-                            let mut peer_data = PeerListData::new();
-                            let mut peers_vec = Vec::new();
-                            for peer in peers.iter() {
-                                let data = peer.clone();
-                                let peer_slint = PeerListData::create_peer(data.0, data.1);
-                                peers_vec.push(peer_slint);
-                            }
-                            peer_data.set_data(peers_vec);
-                            app.unwrap().global::<PeerList>().set_peers(peer_data.get_data().clone().into());
-                        });      
-                        if res.is_err(){
-                            error!("Error updating peers: {:?}", res.err().unwrap());
-                        }  
-                        //app_weak2.unwrap().global::<PeerList>().set_peers(peer_data.get_data().into());
-                        //app_clone2.global::<PeerList>().set_peers(peer_data.get_data().into());
-                    }
-                    thread::sleep(std::time::Duration::from_millis(100));
-                }
-            });
+            ChatData::watch(app_weak.clone(), move || server_arc5.get_chat());
+            watch_audio_errors(app_weak.clone(), move || server_arc6.take_audio_error());
+            PeerListData::watch(app_weak, move || server_arc4.get_peers());
         });
 
     });
+    //The clipboard has to outlive the copy on some platforms
+    let clipboard: Rc<RefCell<Option<arboard::Clipboard>>> = Rc::new(RefCell::new(None));
+    app.global::<Signaling>().on_copy_invite(move ||{
+        let app = app_weak4.unwrap();
+        let globals = app.global::<Signaling>();
+        let mut room = globals.get_room().to_string();
+        if room.is_empty(){
+            room = signaling::server::DEFAULT_ROOM.to_string();
+        }
+        let mut invite = Invite::new(globals.get_address().to_string(), globals.get_key().to_string(), room);
+        let hours = globals.get_invite_hours();
+        if hours > 0{
+            invite = invite.expiring_in(std::time::Duration::from_secs(hours as u64 * 3600));
+        }
+        let uri = match invite.to_uri(){
+            Ok(uri) => uri,
+            Err(e) => {
+                error!("Failed to create the invite: {}", e);
+                globals.set_error(format!("Failed to create the invite: {}", e).into());
+                return;
+            }
+        };
+        globals.set_invite(uri.clone().into());
+
+        let mut clipboard = clipboard.borrow_mut();
+        if clipboard.is_none(){
+            *clipboard = arboard::Clipboard::new().map_err(|e| error!("Clipboard not available: {}", e)).ok();
+        }
+        if let Some(clipboard) = clipboard.as_mut(){
+            if let Err(e) = clipboard.set_text(uri){
+                error!("Failed to copy the invite: {}", e);
+            }
+        }
+    });
+
+    app.global::<Signaling>().on_join_invite(move |uri|{
+        let app = app_weak5.unwrap();
+        let globals = app.global::<Signaling>();
+        let invite = match Invite::parse(uri.as_str()){
+            Ok(invite) => invite,
+            Err(e) => {
+                error!("Can't join from the invite: {}", e);
+                globals.set_error(format!("Can't join from the invite: {}", e).into());
+                return;
+            }
+        };
+        globals.set_address(invite.address.clone().into());
+        globals.set_key(invite.key.clone().into());
+        globals.set_room(invite.room.clone().into());
+        globals.invoke_connect(invite.address.into(), invite.key.into(), invite.room.into());
+    });
+
+    let lan_rooms: Arc<Mutex<Vec<lan::LanRoom>>> = Arc::new(Mutex::new(Vec::new()));
+    match LanBrowser::start(){
+        Ok(browser) => LanRoomData::watch(app.as_weak(), browser, lan_rooms.clone()),
+        Err(e) => warn!("Can't look for rooms on the network: {}", e),
+    }
+    let app_weak6 = app.as_weak();
+    app.global::<Lan>().on_join(move |idx|{
+        let app = app_weak6.unwrap();
+        let globals = app.global::<Signaling>();
+        let try_room = lan_rooms.lock().unwrap().get(idx as usize).cloned();
+        if try_room.is_none(){
+            return;
+        }
+        let room = try_room.unwrap();
+        //Rooms that don't share their key take the one typed in
+        let key = room.key.unwrap_or(globals.get_key().to_string());
+        if key.is_empty(){
+            globals.set_error(format!("Room {} needs a key, type it in and join again", room.room).into());
+            return;
+        }
+        globals.set_address(room.address.clone().into());
+        globals.set_key(key.clone().into());
+        globals.set_room(room.room.clone().into());
+        globals.invoke_connect(room.address.into(), key.into(), room.room.into());
+    });
+
     //TODO: implement a socket to read from AudioCapture
-    app.global::<Signaling>().on_connect(move |addr, key|{
+    app.global::<Signaling>().on_connect(move |addr, key, room|{
+        if app_clone3.global::<Signaling>().get_connecting(){
+            return;
+        }
         let backend = backend_arc4.lock().unwrap().clone();
         let username = app_clone3.global::<SelfPeer>().get_name().to_string();
 
-        let client = SignalingClient::new(username, addr.to_string(), key.to_string());
-        //cs_instance_clone2.lock().unwrap().0 = Some(client);
+        let room = if room.is_empty() { signaling::server::DEFAULT_ROOM.into() } else { room };
+        let try_resolver = resolver(&app_clone3);
+        if let Err(e) = &try_resolver {
+            app_clone3.global::<Signaling>().set_error(format!("Invalid network settings: {}", e).into());
+            return;
+        }
+        let resolver = try_resolver.unwrap();
+        let presence = self_presence(&app_clone3);
         let playback_name = playback_id_clone4.lock().unwrap().clone();
-        //let cs_cinstance = cs_instance_clone2.clone();        
-
-        println!("Connecting to {}", addr.as_str());
-        println!("Key: {}", key.as_str());
-        app_clone3.global::<Signaling>().set_connected(true);
-
-        //let cd = capture_device_clone5.clone();
-        let bind = capture_device_clone5.lock().unwrap().get_conn_addr();
-        let connect = capture_device_clone5.lock().unwrap().get_queue_addr();
-        info!("Bind: {}", bind);
-        info!("Connect: {}", connect);
-        //let cs_cinstance2 = cs_instance_clone2.clone();
+        app_clone3.global::<Signaling>().set_error(SharedString::default());
+        app_clone3.global::<Signaling>().set_connecting(true);
+
+        let cs_cinstance = cs_instance_clone2.clone();
         let rx = capture_rx_arc2.clone();
         let peer_rx = peercontrol_rx_arc2.clone();
+        let app_weak = app_weak3.clone();
+        //Reaching the host can take up to the connect timeout, the gui keeps running meanwhile
         thread::spawn(move ||{
-            let client_arc = Arc::new(client);
+            let client_arc = match SignalingClient::new(username, addr.to_string(), key.to_string(), room.to_string(), resolver) {
+                Ok(client) => Arc::new(client),
+                Err(e) => {
+                    error!("Failed to join room {} at {}: {}", room, addr, e);
+                    finish_connecting(app_weak, Some(format!("Failed to join room {}: {}", room, e)));
+                    return;
+                }
+            };
+            finish_connecting(app_weak.clone(), None);
+            client_arc.set_presence(presence);
+            cs_cinstance.lock().unwrap().0 = Some(client_arc.clone());
+            info!("Connected to {} in room {}", addr.as_str(), room.as_str());
+
             let client_arc2 = client_arc.clone();
             let client_arc3 = client_arc.clone();
+            let client_arc4 = client_arc.clone();
+            let client_arc5 = client_arc.clone();
+            let client_arc6 = client_arc.clone();
+            let client_arc7 = client_arc.clone();
             let rx2 = rx.clone();
             let peer_rx2 = peer_rx.clone();
+            let app_weak3 = app_weak.clone();
             thread::spawn(move ||{
-                client_arc2.run(backend, playback_name);
+                if let Err(e) = client_arc2.run(backend, playback_name){
+                    error!("Left room {}: {}", room, e);
+                    show_disconnected(app_weak3, format!("Left room {}: {}", room, e));
+                }
             });
             thread::spawn(move||{
                 let c_rx = rx2.lock().unwrap();
@@ -385,11 +884,45 @@ fn main() {
                     client_arc3.change_peer_volume(id, volume);
                 }
             });
+            //Show the room address if we become the host after a host migration
+            let app_weak2 = app_weak.clone();
+            thread::spawn(move ||{
+                loop{
+                    thread::sleep(std::time::Duration::from_secs(1));
+                    let hosting = client_arc5.get_hosting_address();
+                    if hosting.is_none(){
+                        continue;
+                    }
+                    let listen = hosting.unwrap();
+                    info!("Hosting the room on {}", listen);
+                    let res = slint::invoke_from_event_loop(move ||{
+                        let app = app_weak2.unwrap();
+                        app.global::<Signaling>().set_address(slint::SharedString::from(listen));
+                        app.global::<Signaling>().set_key(key);
+                        app.global::<Signaling>().set_hosting(true);
+                    });
+                    if res.is_err(){
+                        error!("Error updating hosting status: {:?}", res.err().unwrap());
+                    }
+                    break;
+                }
+            });
+            ChatData::watch(app_weak.clone(), move || client_arc6.get_chat());
+            watch_audio_errors(app_weak.clone(), move || client_arc7.take_audio_error());
+            PeerListData::watch(app_weak, move || client_arc4.get_peers());
         });
     });
 
 
     app.run().unwrap();
-    
-    
+
+    //Let the other peers know we are gone instead of waiting for the connection to drop
+    let instance = cs_instance.lock().unwrap();
+    if let Some(client) = instance.0.as_ref(){
+        client.hang_up();
+    }
+    //The peers elect a new host among themselves once we close their connections
+    if let Some(server) = instance.1.as_ref(){
+        server.hang_up();
+    }
 }
@@ -0,0 +1,178 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::aes::AES;
+use crate::signaling::frame;
+use crate::signaling::message::MessageError;
+use crate::signaling::protocol::Protocol;
+
+/// Size of the random challenge sent to every new connection
+pub const NONCE_SIZE: usize = 32;
+//Keeps the proofs from being valid anywhere else the room key is used
+const CONTEXT: &[u8] = b"savi room authentication";
+
+/// Why the server refused a connection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    /// The room doesn't exist or the proof doesn't match its key, the server doesn't tell which
+    WrongKey,
+    /// The host dropped a peer from this address and banned it
+    Banned,
+    /// Every peer id of the room is taken
+    Full,
+    /// The client speaks a protocol version the server can't talk to anymore
+    /// * `version` - The version of the server
+    /// * `oldest` - The oldest version the server accepts
+    Incompatible { version: u16, oldest: u16 },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::WrongKey => write!(f, "wrong room name or key"),
+            Rejection::Banned => write!(f, "banned from the room"),
+            Rejection::Full => write!(f, "the room is full"),
+            Rejection::Incompatible { version, oldest } => write!(
+                f,
+                "this version of Savi is too old for the server, it speaks protocol version {} and needs at least version {}",
+                version, oldest
+            ),
+        }
+    }
+}
+
+/// Messages that go in the clear before the client proves it knows the room key.
+/// The exchange goes as follows:
+/// server: ```Challenge```, client: ```Response``` and its encrypted ```Join```,
+/// server: ```Accepted``` and the encrypted ```Welcome```, or ```Rejected```.
+/// The ```Challenge``` and the ```Response``` have to keep their layout across versions,
+/// it is how both sides find out which version the other one speaks
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AuthMessage {
+    /// Sent by the server as soon as it accepts a connection
+    /// * `protocol` - The protocol spoken by the server
+    /// * `nonce` - The challenge
+    Challenge { protocol: Protocol, nonce: Vec<u8> },
+    /// Sent by the client to prove it knows the room key without sending it
+    /// * `protocol` - The protocol spoken by the client
+    /// * `room` - The room to join
+    /// * `proof` - The output of ```prove``` for the challenge
+    Response { protocol: Protocol, room: String, proof: Vec<u8> },
+    /// The client is in, the ```Welcome``` comes next
+    Accepted,
+    /// The server closes the connection right after sending it
+    Rejected { reason: Rejection },
+}
+
+/// Generates a new challenge
+pub fn challenge() -> Vec<u8> {
+    rand::random::<[u8; NONCE_SIZE]>().to_vec()
+}
+
+fn transcript(nonce: &[u8], room: &str) -> Vec<u8> {
+    let mut transcript = Vec::with_capacity(CONTEXT.len() + nonce.len() + room.len());
+    transcript.extend_from_slice(CONTEXT);
+    transcript.extend_from_slice(nonce);
+    transcript.extend_from_slice(room.as_bytes());
+    transcript
+}
+
+/// Answers a challenge with an HMAC of the challenge and the room name keyed with the room key
+/// # Arguments
+/// * `cipher` - The room cipher
+/// * `nonce` - The challenge of the server
+/// * `room` - The room to join
+pub fn prove(cipher: &AES, nonce: &[u8], room: &str) -> Vec<u8> {
+    cipher.sign(&transcript(nonce, room))
+}
+
+/// Checks the answer of a client to a challenge
+/// # Arguments
+/// * `cipher` - The cipher of the room the client asked for
+/// * `nonce` - The challenge sent to the client
+/// * `room` - The room the client asked for
+/// * `proof` - The answer of the client
+pub fn verify(cipher: &AES, nonce: &[u8], room: &str, proof: &[u8]) -> bool {
+    nonce.len() == NONCE_SIZE && cipher.verify(&transcript(nonce, room), proof)
+}
+
+/// Hashes a resumption token so the other peers of the room can check it after a host
+/// migration without being able to use it themselves
+/// # Arguments
+/// * `token` - The resumption token
+/// # Returns
+/// * `String` - The hex SHA-256 of the token
+pub fn token_digest(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Writes an authentication message as a single unencrypted frame
+/// # Arguments
+/// * `writer` - The stream to write to
+/// * `message` - The message to send
+pub async fn write_auth<W: AsyncWrite + Unpin>(writer: &mut W, message: &AuthMessage) -> Result<(), MessageError> {
+    debug!("Sending {:?}", message);
+    let serialized = bincode::serialize(message).map_err(MessageError::Malformed)?;
+    frame::write_frame(writer, &serialized).await?;
+    Ok(())
+}
+
+/// Reads an authentication message
+/// # Arguments
+/// * `reader` - The stream to read from
+/// # Returns
+/// * `Some(AuthMessage)` - The message
+/// * `None` - If the stream was closed cleanly
+pub async fn read_auth<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<AuthMessage>, MessageError> {
+    let payload = match frame::read_frame(reader).await? {
+        Some(payload) => payload,
+        None => return Ok(None),
+    };
+    let message = bincode::deserialize(&payload).map_err(MessageError::Malformed)?;
+    debug!("Got {:?}", message);
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_proof_with_the_room_key_verifies() {
+        let cipher = AES::new(None).unwrap();
+        let nonce = challenge();
+
+        assert!(verify(&cipher, &nonce, "lobby", &prove(&cipher, &nonce, "lobby")));
+    }
+
+    #[test]
+    fn a_proof_with_another_key_is_rejected() {
+        let nonce = challenge();
+        let proof = prove(&AES::new(None).unwrap(), &nonce, "lobby");
+
+        assert!(!verify(&AES::new(None).unwrap(), &nonce, "lobby", &proof));
+    }
+
+    #[test]
+    fn a_proof_only_answers_its_own_challenge_and_room() {
+        let cipher = AES::new(None).unwrap();
+        let nonce = challenge();
+        let proof = prove(&cipher, &nonce, "lobby");
+
+        assert!(!verify(&cipher, &challenge(), "lobby", &proof));
+        assert!(!verify(&cipher, &nonce, "other", &proof));
+        assert!(!verify(&cipher, &nonce[..NONCE_SIZE - 1], "lobby", &prove(&cipher, &nonce[..NONCE_SIZE - 1], "lobby")));
+    }
+
+    #[test]
+    fn token_digest_is_the_hex_sha256_of_the_token() {
+        assert_eq!(token_digest("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(token_digest("abc"), token_digest("abc"));
+        assert_ne!(token_digest("abc"), token_digest("abd"));
+    }
+}
@@ -0,0 +1,63 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::signaling::PeerId;
+
+/// Longest chat message in bytes, longer ones are refused
+pub const MAX_CHAT_LENGTH: usize = 4096;
+/// Chat messages kept in the history, the oldest ones are dropped first
+pub const MAX_HISTORY: usize = 500;
+
+/// A line of the chat history
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatEntry {
+    /// Who wrote it
+    pub from: PeerId,
+    pub username: String,
+    pub text: String,
+    /// Sent only to us, or only to one peer if we wrote it
+    pub direct: bool,
+}
+
+/// Chat history of the room as seen by us
+pub struct ChatLog {
+    entries: Mutex<VecDeque<ChatEntry>>,
+}
+
+impl ChatLog {
+    pub fn new() -> Self {
+        ChatLog {
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Checks the text of a chat message
+    /// # Returns
+    /// * `bool` - ```false``` if it is empty or longer than ```MAX_CHAT_LENGTH```
+    pub fn is_valid(text: &str) -> bool {
+        !text.trim().is_empty() && text.len() <= MAX_CHAT_LENGTH
+    }
+
+    /// Adds a line to the history, dropping the oldest one if it is full
+    pub fn push(&self, entry: ChatEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == MAX_HISTORY {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Returns the whole history, oldest first
+    pub fn history(&self) -> Vec<ChatEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl Default for ChatLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
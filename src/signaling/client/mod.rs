@@ -4,223 +4,687 @@
 use log::{debug, error, info, warn};
 
 use std::collections::HashMap;
-use std::io::{Read, Write};
 use std::net::TcpListener;
-use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::runtime::Runtime;
+use tokio::sync::Notify;
 
 use crate::aes::AES;
-use crate::audio::playback::AudioPlayback;
-use crate::audio::{Audio, playback};
-use crate::audio_peer::{AudioPeer, self};
-use crate::{signaling};
+use crate::audio::AudioError;
+use crate::signaling::{PeerId, SignalingError, HOST_ID};
+use crate::signaling::auth::{self, AuthMessage};
+use crate::signaling::chat::ChatEntry;
+use crate::signaling::discovery::AddressResolver;
+use crate::signaling::heartbeat::Heartbeat;
+use crate::signaling::mesh::{self, Mesh};
+use crate::signaling::message::{self, Envelope, Member, MessageError, Presence, SignalingMessage};
+use crate::signaling::protocol::{Capabilities, Protocol};
+use crate::signaling::server::{SignalingServer, RESUME_TIMEOUT};
+use crate::signaling::transport::{self, BoxedStream, Reader, Writer};
+
+/// Time the host has to accept our connection and let us into the room
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time each attempt to reach the elected host takes, a connection that takes longer is given up
+const MIGRATION_RETRY: Duration = Duration::from_millis(500);
+/// Attempts before giving up on an elected host and electing the next one
+const MIGRATION_ATTEMPTS: u32 = 20;
+/// First wait between attempts to reconnect to the host, it doubles after every attempt
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(250);
+/// Longest wait between attempts to reconnect to the host
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(4);
+
+/// What the host told us when we joined
+struct Session {
+    id: PeerId,
+    host: PeerId,
+    //The other members of the room
+    members: Vec<Member>,
+    heartbeat: Heartbeat,
+    token: String,
+    //Features both we and the host support
+    capabilities: Capabilities,
+}
 
 pub struct SignalingClient {
-    id: u8,
-    username: String,
-    stream: TcpStream,
+    runtime: Runtime,
+    //Read half of the connection to the host, taken by ```run```
+    reader: Mutex<Option<Reader>>,
+    writer: tokio::sync::Mutex<Writer>,
     cipher: Arc<AES>,
-    //(username, address_candidate, peer)
-    audio_peers: Arc<Mutex<HashMap<u8, (String, String, AudioPeer)>>>,
+    room: String,
+    //Address of the current host
+    address: Mutex<String>,
+    //Gets our id back if we lose the connection to the host
+    token: Mutex<String>,
+    //Everyone in the room, us included
+    members: Mutex<HashMap<PeerId, Member>>,
+    //Peer id of the current host
+    host: AtomicU16,
+    //Keepalive settings of the current host
+    heartbeat: Mutex<Heartbeat>,
+    //Features both we and the current host support
+    capabilities: Mutex<Capabilities>,
+    //Listener used to take over the room if we get elected as the new host, and the address we advertise for it
+    standby: Mutex<Option<(TcpListener, String)>>,
+    mesh: Arc<Mesh>,
+    hung_up: AtomicBool,
+    //Why the host won't take us back or dropped us, ```run``` returns it
+    failure: Mutex<Option<SignalingError>>,
+    //Stops listening to the host
+    close: Notify,
+    promoted: Mutex<Option<Arc<SignalingServer>>>,
 }
 impl SignalingClient {
-    pub fn new(username: String, address: String, key: String) -> Self {
-        let cipher = Arc::new(AES::new(Some(key)));
-        let audio_peers = Arc::new(Mutex::new(HashMap::new()));
-        let try_stream = TcpStream::connect(address);
-        if try_stream.is_err() {
-            println!("Err: {:?}", try_stream.err());
-            panic!("Failed to connect to server");
-        } else {
-            debug!("Connected to server");
-        }
-        let mut stream = try_stream.unwrap();
-        //stream.write(&[1]);
-        thread::sleep(std::time::Duration::from_millis(10));
-        let buf = &mut [0; 1024];
-        let try_recv = stream.read(buf);
-        if try_recv.is_err() {
-            panic!("Failed to read id");
-        }
-        let size = try_recv.unwrap();
-        let encrypted = String::from_utf8_lossy(&buf[0..size]).to_string();
-        debug!("Got encrypted {}", encrypted);
-        let decrypted = cipher.decrypt(encrypted);
-        let split = decrypted.split("¬").collect::<Vec<&str>>();
-        let id = split[1].parse::<u8>().unwrap();
-        debug!("Peer id is {}", id);
-        SignalingClient {
-            id,
-            username,
-            stream,
-            cipher,
-            audio_peers,
-        }
-    }
-    pub fn run(&self, backend: String, playback_name: String) {
-        let mut stream = self.stream.try_clone().unwrap();
+    /// Connects to the host and joins the room
+    /// # Arguments
+    /// * `username` - Our username
+    /// * `address` - The address of the host, ```host:port``` or a ```ws://```/```wss://``` URL
+    /// * `key` - The room key
+    /// * `room` - The room name
+    /// * `resolver` - Finds our address candidates
+    /// # Errors
+    /// * `SignalingError::Crypto` - If the key is not valid
+    /// * `SignalingError::Message` - If the host can't be reached or doesn't let us in
+    /// * `SignalingError::TimedOut` - If the host doesn't let us in within ```CONNECT_TIMEOUT```
+    /// * `SignalingError::Io` - If the async runtime can't start
+    pub fn new(username: String, address: String, key: String, room: String, resolver: AddressResolver) -> Result<Self, SignalingError> {
+        let cipher = Arc::new(AES::new(Some(key))?);
+        let runtime = Runtime::new()?;
+        let mut stream = runtime
+            .block_on(async { tokio::time::timeout(CONNECT_TIMEOUT, transport::connect(&address)).await })
+            .map_err(|_| SignalingError::TimedOut)?
+            .map_err(MessageError::Io)?;
+        debug!("Connected to server");
+        let try_standby = resolver.binding().listen().and_then(|listener| {
+            let address = resolver.advertise(&listener)?;
+            Ok((listener, address))
+        });
+        if let Err(e) = &try_standby {
+            warn!("We won't be able to take over the room if the host leaves: {}", e);
+        }
+        let standby = try_standby.ok();
+        let standby_address = Self::standby_address(&standby);
+        let join = Self::join(&mut stream, &cipher, &room, standby_address.clone(), None, None);
+        let session = runtime.block_on(async { tokio::time::timeout(CONNECT_TIMEOUT, join).await }).map_err(|_| SignalingError::TimedOut)??;
+        let id = session.id;
+        debug!("Peer id is {}, members of room {}: {:?}", id, room, session.members);
+
+        let mut roster: HashMap<PeerId, Member> = session.members.into_iter().map(|m| (m.id, m)).collect();
+        roster.insert(id, Member { id, standby: standby_address, token_digest: auth::token_digest(&session.token) });
+        let (reader, writer) = tokio::io::split(stream);
+        Ok(SignalingClient {
+            runtime,
+            reader: Mutex::new(Some(reader)),
+            writer: tokio::sync::Mutex::new(writer),
+            cipher: cipher.clone(),
+            room: room.clone(),
+            address: Mutex::new(address),
+            token: Mutex::new(session.token),
+            members: Mutex::new(roster),
+            host: AtomicU16::new(session.host),
+            heartbeat: Mutex::new(session.heartbeat),
+            capabilities: Mutex::new(session.capabilities),
+            standby: Mutex::new(standby),
+            mesh: Arc::new(Mesh::new(id, room, username, resolver, cipher.clone())),
+            hung_up: AtomicBool::new(false),
+            failure: Mutex::new(None),
+            close: Notify::new(),
+            promoted: Mutex::new(None),
+        })
+    }
+
+    fn standby_address(standby: &Option<(TcpListener, String)>) -> String {
+        standby.as_ref().map(|(_, address)| address.clone()).unwrap_or_default()
+    }
+
+    /// Proves we know the room key, sends the join message and waits for the welcome
+    /// # Arguments
+    /// * `stream` - A new connection to the host
+    /// * `cipher` - The room cipher
+    /// * `room` - The room name
+    /// * `standby` - Our standby address
+    /// * `resume` - The id we had before losing the connection or with the previous host
+    /// * `token` - The resumption token of that id, ```None``` for a new peer
+    /// # Errors
+    /// * `MessageError::Rejected` - If the host refused us, a wrong key for example
+    /// * `MessageError::Incompatible` - If the host speaks a protocol version we can't talk to
+    async fn join(stream: &mut BoxedStream, cipher: &AES, room: &str, standby: String, resume: Option<PeerId>, token: Option<String>) -> Result<Session, MessageError> {
+        let (server, nonce) = match auth::read_auth(stream).await? {
+            Some(AuthMessage::Challenge { protocol, nonce }) => (protocol, nonce),
+            Some(message) => return Err(MessageError::UnexpectedAuth(message)),
+            None => return Err(MessageError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+        };
+        let protocol = Protocol::local();
+        protocol.negotiate(&server).map_err(MessageError::Incompatible)?;
+        let proof = auth::prove(cipher, &nonce, room);
+        auth::write_auth(stream, &AuthMessage::Response { protocol, room: room.to_string(), proof }).await?;
+        let join = Envelope::new(HOST_ID, resume.unwrap_or(HOST_ID), SignalingMessage::Join { room: room.to_string(), standby, resume, token });
+        message::write_message(stream, cipher, &join).await?;
+        match auth::read_auth(stream).await? {
+            Some(AuthMessage::Accepted) => {}
+            Some(AuthMessage::Rejected { reason }) => return Err(MessageError::Rejected(reason)),
+            Some(message) => return Err(MessageError::UnexpectedAuth(message)),
+            None => return Err(MessageError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+        }
+        match message::read_message(stream, cipher).await? {
+            Some((Envelope { from, message: SignalingMessage::Welcome { id, room: welcomed, members, heartbeat, token, protocol: host }, .. }, _))
+                if welcomed == room =>
+            {
+                let capabilities = protocol.negotiate(&host).map_err(MessageError::Incompatible)?;
+                debug!("The host speaks protocol version {} with {}", host.version, capabilities);
+                Ok(Session { id, host: from, members, heartbeat, token, capabilities })
+            }
+            Some((envelope, _)) => Err(MessageError::Unexpected(envelope.message)),
+            None => Err(MessageError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+        }
+    }
+
+    /// Takes part in the room until ```hang_up``` is called, blocks the calling thread
+    /// # Arguments
+    /// * `backend` - The audio backend name
+    /// * `playback_name` - The playback device for our audio links
+    /// # Errors
+    /// * `SignalingError::Message` - If the host wouldn't take us back after losing the connection
+    /// * `SignalingError::Disconnected` - If the host is gone and nobody could take over the room
+    /// * `SignalingError::Kicked` - If the host dropped us from the room
+    pub fn run(&self, backend: String, playback_name: String) -> Result<(), SignalingError> {
+        self.mesh.set_playback(backend, playback_name);
+        let try_reader = self.reader.lock().unwrap().take();
+        if try_reader.is_none() {
+            error!("The client is already running");
+            return Ok(());
+        }
+        self.runtime.block_on(self.serve(try_reader.unwrap()))
+    }
+
+    async fn serve(&self, mut reader: Reader) -> Result<(), SignalingError> {
         //Announce
-        for i in 0..self.id {
-            let address_candidate = signaling::get_address_ipv6();
-            let announce = format!(
-                "{}¬{}¬ann¬{}¬{}",
-                i,
-                self.id,
-                self.username.clone(),
-                address_candidate
-            );
-            let encrypted = self.cipher.encrypt(announce);
-            debug!("Sending encrypted {}", encrypted);
-            stream.write(encrypted.as_bytes()).unwrap();
-
-            let mut peers = self.audio_peers.lock().unwrap();
-            let audio_peer = AudioPeer::new(address_candidate);
-            let item = ("".to_string(), "".to_string(), audio_peer);
-            peers.insert(i, item);
-        }
-        thread::scope(move |scope| {
-            let playback_mainclone = playback_name.clone();
-            let buf = &mut [0; 2048];
-            let audio_peers = self.audio_peers.clone();
-            let bkc = backend.clone();
-            loop {
-                let backend = bkc.clone(); 
-                let try_recv = stream.read(buf);
-                if try_recv.is_err() {
-                    error!("Failed to read from server, connection lost");
+        self.announce().await;
+        loop {
+            let host_left = tokio::select! {
+                host_left = self.listen(&mut reader) => host_left,
+                _ = self.expire_handshakes() => false,
+                _ = self.update_speaking() => false,
+                _ = self.close.notified() => false,
+            };
+            if self.hung_up.load(Ordering::Relaxed) {
+                break;
+            }
+            if !host_left {
+                if let Some(new_reader) = self.reconnect().await {
+                    reader = new_reader;
+                    continue;
+                }
+                if self.hung_up.load(Ordering::Relaxed) {
                     break;
                 }
-                let size = try_recv.unwrap();
-                if size == 0 {
+            }
+            match self.migrate().await {
+                Some(new_reader) => reader = new_reader,
+                None => break,
+            }
+        }
+        if let Some(e) = self.failure.lock().unwrap().take() {
+            return Err(e);
+        }
+        //We took over the room, keep it going
+        let promoted = self.promoted.lock().unwrap().clone();
+        match promoted {
+            Some(server) => server.serve().await,
+            None if self.hung_up.load(Ordering::Relaxed) => Ok(()),
+            None => Err(SignalingError::Disconnected),
+        }
+    }
+
+    /// Drops the stale negotiations and relays the voice of the peers we can't reach
+    /// every second, never returns
+    async fn expire_handshakes(&self) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            self.mesh.expire_handshakes();
+            for notice in self.mesh.relay_unreachable(Instant::now()) {
+                if let Err(e) = self.send_relayed(&notice).await {
+                    warn!("Failed to switch peer {} to the relay: {}", notice.target, e);
+                }
+            }
+        }
+    }
+
+    /// Sends a ```Relay``` or ```Voice``` message, if the host can relay them
+    async fn send_relayed(&self, envelope: &Envelope) -> Result<(), MessageError> {
+        if !self.capabilities.lock().unwrap().contains(Capabilities::RELAY) {
+            debug!("The host can't relay {:?}", envelope.message);
+            return Ok(());
+        }
+        self.send(envelope).await
+    }
+
+    /// Lets everyone know when we start or stop speaking, never returns
+    async fn update_speaking(&self) {
+        let mut ticker = tokio::time::interval(mesh::SPEAKING_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let try_presence = self.mesh.update_speaking(Instant::now());
+            if try_presence.is_none() || !self.capabilities.lock().unwrap().contains(Capabilities::PRESENCE) {
+                continue;
+            }
+            if let Err(e) = self.send(&try_presence.unwrap()).await {
+                debug!("Failed to send our presence: {}", e);
+            }
+        }
+    }
+
+    /// Sends a message to the host
+    async fn send(&self, envelope: &Envelope) -> Result<(), MessageError> {
+        let mut writer = self.writer.lock().await;
+        message::write_message(&mut *writer, &self.cipher, envelope).await
+    }
+
+    /// Starts a negotiation with every other member of the room
+    async fn announce(&self) {
+        let id = self.mesh.id();
+        let members: Vec<PeerId> = self.members.lock().unwrap().keys().copied().filter(|member| *member != id).collect();
+        self.announce_to(members).await;
+    }
+
+    /// Starts a negotiation with some members of the room
+    async fn announce_to(&self, members: Vec<PeerId>) {
+        for member in members {
+            //Announcing may query the STUN servers
+            let mesh = self.mesh.clone();
+            let try_announce = tokio::task::spawn_blocking(move || mesh.announce(member)).await;
+            let announce = match try_announce {
+                Ok(Some(announce)) => announce,
+                _ => {
+                    error!("Failed to announce ourselves to peer {}", member);
+                    continue;
+                }
+            };
+            if let Err(e) = self.send(&announce).await {
+                error!("Failed to announce ourselves to peer {}: {}", member, e);
+            }
+        }
+    }
+
+    /// Handles the messages from the host until the connection is lost or the host goes quiet
+    /// # Returns
+    /// * `bool` - ```true``` if the host hung up, ```false``` if the connection was lost
+    async fn listen(&self, reader: &mut Reader) -> bool {
+        loop {
+            let timeout = self.heartbeat.lock().unwrap().timeout;
+            let try_read = tokio::time::timeout(timeout, message::read_message(reader, &self.cipher)).await;
+            if try_read.is_err() {
+                error!("The server didn't ping us for {:?}, connection lost", timeout);
+                return false;
+            }
+            let envelope = match try_read.unwrap() {
+                Ok(Some((envelope, _))) => envelope,
+                Ok(None) => {
                     error!("Failed to read from server, connection lost");
-                    break;
+                    return false;
+                }
+                Err(MessageError::Io(e)) => {
+                    error!("Failed to read from server, connection lost: {}", e);
+                    return false;
                 }
-                let encrypted = String::from_utf8_lossy(&buf[0..size]).to_string();
-                debug!("Got encrypted {}", encrypted);
-                let decrypted = self.cipher.decrypt(encrypted);
-                debug!("Got decrypted {}", decrypted);
-                let split = decrypted.split("¬").collect::<Vec<&str>>();
-                let event = split[2];
-                match event {
-                    "ann" => {
-                        let peer_id = split[1].parse::<u8>().unwrap();
-                        let peer_username = split[3].to_string();
-                        let peer_address_candidate = split[4].to_string();
-
-                        let adress_candidate = signaling::get_address_ipv6();
-                        let username = self.username.clone();
-
-                        let mut peers = audio_peers.lock().unwrap();
-                        let audio_peer = AudioPeer::new(adress_candidate.clone());
-                        let item = (peer_username, peer_address_candidate, audio_peer);
-                        peers.insert(peer_id, item);
-
-                        let ack = format!(
-                            "{}¬{}¬ack¬{}¬{}",
-                            peer_id, self.id, username, adress_candidate
-                        );
-                        let encrypted = self.cipher.encrypt(ack);
-                        debug!("Sending encrypted {}", encrypted);
-                        stream.write(encrypted.as_bytes()).unwrap();
+                Err(e) => {
+                    warn!("Dropping message: {}", e);
+                    continue;
+                }
+            };
+            //Keep track of who is in the room in case we have to elect a new host
+            if envelope.from == HOST_ID {
+                match &envelope.message {
+                    SignalingMessage::Ping => {
+                        let pong = Envelope::new(HOST_ID, self.mesh.id(), SignalingMessage::Pong);
+                        if let Err(e) = self.send(&pong).await {
+                            error!("Failed to answer the ping of the server: {}", e);
+                        }
+                        continue;
                     }
-                    "ack" => {
-                        let peer_id = split[1].parse::<u8>().unwrap();
-                        let username = split[3];
-                        let address_candidate = split[4];
-
-                        let mut peers = audio_peers.lock().unwrap();
-                        peers.get_mut(&peer_id).unwrap().0 = username.to_string();
-                        peers.get_mut(&peer_id).unwrap().1 = address_candidate.to_string();
-
-                        let ok = format!("{}¬{}¬ok", peer_id, self.id);
-                        let encrypted = self.cipher.encrypt(ok);
-                        debug!("Sending encrypted {}", encrypted);
-                        stream.write(encrypted.as_bytes()).unwrap();
+                    SignalingMessage::Joined { member } => {
+                        self.members.lock().unwrap().insert(member.id, member.clone());
+                        continue;
                     }
-                    "ok" => {
-                        let peer_id = split[1].parse::<u8>().unwrap();
-
-                        let ok = format!("{}¬{}¬ko", peer_id, self.id);
-                        let encrypted = self.cipher.encrypt(ok);
-                        debug!("Sending encrypted {}", encrypted);
-                        stream.write(encrypted.as_bytes()).unwrap();
-
-                        let audio_peers_clone = audio_peers.clone();
-                        let playback_clone = playback_mainclone.clone();
-                        scope.spawn(move ||{
-                            let peers = audio_peers_clone.lock().unwrap();
-                            let item = peers.get(&peer_id);
-                            let (_, address, peer) = item.unwrap();
-
-                            let playback_id = Audio::get_device_id(backend.clone() ,&playback_clone, crate::audio::DeviceKind::Playback).unwrap();
-                            let playback_config = AudioPlayback::create_config(playback_id, 2, 48_000);
-                            peer.connect(address.clone(), backend.clone(), playback_config);
-                            drop(peers);
-                            loop{
-                                thread::sleep(std::time::Duration::from_millis(1));
-                            }
-                            
-                        }); 
+                    SignalingMessage::Leave { id } if *id == self.host.load(Ordering::Relaxed) => {
+                        info!("The host hung up");
+                        return true;
                     }
-                    "ko" => {
-                        let peer_id = split[1].parse::<u8>().unwrap();
-
-                        let audio_peers_clone = audio_peers.clone();
-                        let playback_clone = playback_mainclone.clone();
-                        scope.spawn(move ||{
-                            let peers = audio_peers_clone.lock().unwrap();
-                            let item = peers.get(&peer_id);
-                            let (_, address, peer) = item.unwrap();
-
-                            let playback_id = Audio::get_device_id(backend.clone(), &playback_clone, crate::audio::DeviceKind::Playback).unwrap();
-                            let playback_config = AudioPlayback::create_config(playback_id, 2, 48_000);
-                            peer.connect(address.clone(), backend.clone(), playback_config);
-                            drop(peers);
-                            loop{
-                                thread::sleep(std::time::Duration::from_millis(1));
-                            }
-                            
-                        });  
+                    SignalingMessage::Leave { id } if *id == self.mesh.id() => {
+                        warn!("The host dropped us from the room");
+                        *self.failure.lock().unwrap() = Some(SignalingError::Kicked);
+                        self.hung_up.store(true, Ordering::Relaxed);
+                        self.stop_mesh();
+                        return false;
                     }
-                    _ => {
-                        error!("Unknown event {}", event);
+                    SignalingMessage::Leave { id } => {
+                        self.members.lock().unwrap().remove(id);
                     }
+                    _ => {}
                 }
             }
-        });
+            //The mesh may query STUN servers and open audio devices
+            let mesh = self.mesh.clone();
+            let replies = tokio::task::spawn_blocking(move || mesh.handle(envelope)).await.unwrap_or_default();
+            for reply in replies {
+                if let Err(e) = self.send(&reply).await {
+                    error!("Failed to send message to server: {}", e);
+                }
+            }
+        }
     }
-    pub fn send_opus(&self, opus_packet: Vec<u8>) {
-        let trylock = self.audio_peers.try_lock();
-        if trylock.is_err(){
-            error!("Failed to lock audio peers, err:{}", trylock.err().unwrap());
+
+    /// Elects a new host after losing the current one and moves the signaling to it.
+    /// Every peer runs the same election over the same member list, so they all
+    /// pick the lowest id that can host the room
+    /// # Returns
+    /// * `Some(Reader)` - The connection to the new host
+    /// * `None` - If we are the new host or there is nobody left to host the room
+    async fn migrate(&self) -> Option<Reader> {
+        let old_host = self.host.load(Ordering::Relaxed);
+        warn!("Lost the connection with the host {}, electing a new one", old_host);
+        self.members.lock().unwrap().remove(&old_host);
+        self.mesh.remove_peer(old_host);
+
+        let mut unreachable = Vec::new();
+        loop {
+            let try_elected = elect(&self.members.lock().unwrap(), &unreachable);
+            if try_elected.is_none() {
+                error!("Nobody is left to host the room");
+                return None;
+            }
+            let elected = try_elected.unwrap();
+            if elected.id == self.mesh.id() {
+                self.promote();
+                return None;
+            }
+            info!("Peer {} is the new host", elected.id);
+            match self.rehome(&elected).await {
+                Ok(reader) => return Some(reader),
+                Err(e) => {
+                    warn!("Failed to reach the new host {}: {}", elected.id, e);
+                    unreachable.push(elected.id);
+                }
+            }
+        }
+    }
+
+    /// Reconnects to the host after losing the connection, keeps trying with an
+    /// increasing delay for as long as the host keeps our id
+    /// # Returns
+    /// * `Some(Reader)` - The new connection to the host
+    /// * `None` - If the host is gone and a new one has to be elected
+    async fn reconnect(&self) -> Option<Reader> {
+        let address = self.address.lock().unwrap().clone();
+        warn!("Lost the connection with the host, reconnecting to {}", address);
+        let deadline = Instant::now() + RESUME_TIMEOUT;
+        let mut delay = RECONNECT_MIN_DELAY;
+        while Instant::now() < deadline && !self.hung_up.load(Ordering::Relaxed) {
+            //An unreachable host would keep us waiting for the system timeout, well past our id
+            let try_connect = tokio::time::timeout(deadline.saturating_duration_since(Instant::now()), transport::connect(&address))
+                .await
+                .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+            match try_connect {
+                Ok(stream) => {
+                    //The guard can't be held across the await, resume takes the lock again
+                    let token = self.token.lock().unwrap().clone();
+                    match self.resume(stream, token).await {
+                        Ok(reader) => {
+                            info!("Reconnected to the host");
+                            return Some(reader);
+                        }
+                        //The host is up but it won't take us back
+                        Err(e @ (MessageError::Rejected(_) | MessageError::Incompatible(_))) => {
+                            error!("Can't get back in the room: {}", e);
+                            self.hung_up.store(true, Ordering::Relaxed);
+                            self.stop_mesh();
+                            *self.failure.lock().unwrap() = Some(e.into());
+                            return None;
+                        }
+                        Err(e) => warn!("Failed to rejoin the room: {}", e),
+                    }
+                }
+                //Nobody is listening on the host address anymore
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    warn!("The host is gone: {}", e);
+                    return None;
+                }
+                Err(e) => debug!("Failed to reconnect to the host: {}", e),
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+        None
+    }
+
+    /// Connects to the new host and gets our id back
+    /// # Arguments
+    /// * `host` - The elected host
+    async fn rehome(&self, host: &Member) -> Result<Reader, MessageError> {
+        let mut attempts = 0;
+        let mut stream = loop {
+            //Every attempt takes MIGRATION_RETRY at most so we give up on the host when the other peers do
+            let started = Instant::now();
+            let try_connect = tokio::time::timeout(MIGRATION_RETRY, transport::connect(&host.standby))
+                .await
+                .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+            match try_connect {
+                Ok(stream) => break stream,
+                Err(e) => {
+                    attempts += 1;
+                    if attempts >= MIGRATION_ATTEMPTS {
+                        return Err(MessageError::Io(e));
+                    }
+                    tokio::time::sleep(MIGRATION_RETRY.saturating_sub(started.elapsed())).await;
+                }
+            }
+        };
+        //The new host got the digest of our token from the previous one
+        let token = self.token.lock().unwrap().clone();
+        let reader = self.resume(stream, token).await?;
+        *self.address.lock().unwrap() = host.standby.clone();
+        Ok(reader)
+    }
+
+    /// Joins the room again on a new connection, asking for our id back.
+    /// The links with the peers that are still in the room stay up
+    /// # Arguments
+    /// * `stream` - The new connection to the host
+    /// * `token` - Our resumption token
+    async fn resume(&self, mut stream: BoxedStream, token: String) -> Result<Reader, MessageError> {
+        let standby = Self::standby_address(&self.standby.lock().unwrap());
+        //The host could still be busy with our old connection, don't wait for it forever
+        let try_join = tokio::time::timeout(
+            RESUME_TIMEOUT,
+            Self::join(&mut stream, &self.cipher, &self.room, standby.clone(), Some(self.mesh.id()), Some(token)),
+        )
+        .await;
+        let session = try_join.map_err(|_| MessageError::Io(std::io::ErrorKind::TimedOut.into()))??;
+        let id = session.id;
+
+        let rejoined = id != self.mesh.id();
+        if rejoined {
+            warn!("The host gave us the id {}, joining the room again", id);
+            self.mesh.reset(id);
+        }
+        let mut roster: HashMap<PeerId, Member> = session.members.into_iter().map(|m| (m.id, m)).collect();
+        roster.insert(id, Member { id, standby, token_digest: auth::token_digest(&session.token) });
+        //Drop the links with the peers that left while we were away
+        let linked: Vec<PeerId> = self.mesh.get_peers().into_iter().map(|(peer, _, _, _)| peer).collect();
+        for peer in linked.iter() {
+            if !roster.contains_key(peer) {
+                self.mesh.remove_peer(*peer);
+            }
+        }
+        //The announcements of the peers that joined while we were away never reached us
+        let unlinked: Vec<PeerId> = roster.keys().copied().filter(|peer| *peer != id && !linked.contains(peer)).collect();
+        *self.members.lock().unwrap() = roster;
+        self.host.store(session.host, Ordering::Relaxed);
+        *self.heartbeat.lock().unwrap() = session.heartbeat;
+        *self.token.lock().unwrap() = session.token;
+        *self.capabilities.lock().unwrap() = session.capabilities;
+        let (reader, writer) = tokio::io::split(stream);
+        *self.writer.lock().await = writer;
+        if rejoined {
+            self.announce().await;
+        } else {
+            self.announce_to(unlinked).await;
+        }
+        Ok(reader)
+    }
+
+    /// Takes over the room with our standby listener
+    fn promote(&self) {
+        let try_listener = self.standby.lock().unwrap().take();
+        if try_listener.is_none() {
+            error!("We were elected as the new host but we can't host");
             return;
         }
-        let peers = trylock.unwrap();
-        debug!("N peers: {}", peers.len());
-        for (_, _, peer) in peers.values() {
-            debug!("Sending opus packet to peer");
-            peer.send(opus_packet.clone());
+        let id = self.mesh.id();
+        let members = self.members.lock().unwrap().values().filter(|m| m.id != id).cloned().collect();
+        let heartbeat = *self.heartbeat.lock().unwrap();
+        let (listener, address) = try_listener.unwrap();
+        let server = SignalingServer::promote(listener, address, self.cipher.clone(), self.mesh.clone(), members)
+            .with_heartbeat(heartbeat);
+        info!("We are the new host, listening on {}", server.get_listen_address());
+        *self.promoted.lock().unwrap() = Some(Arc::new(server));
+    }
+
+    /// Returns the address we are hosting the room on if we took it over after a host migration
+    pub fn get_hosting_address(&self) -> Option<String> {
+        self.promoted.lock().unwrap().as_ref().map(|server| server.get_address())
+    }
+
+    /// Tells the server we are leaving and closes the connection
+    pub fn hang_up(&self) {
+        self.hung_up.store(true, Ordering::Relaxed);
+        let promoted = self.promoted.lock().unwrap().clone();
+        if let Some(server) = promoted {
+            server.hang_up();
+        } else {
+            self.runtime.block_on(async {
+                let leave = Envelope::new(HOST_ID, self.mesh.id(), SignalingMessage::Leave { id: self.mesh.id() });
+                if let Err(e) = self.send(&leave).await {
+                    warn!("Failed to hang up: {}", e);
+                }
+                let _ = self.writer.lock().await.shutdown().await;
+            });
+        }
+        self.close.notify_one();
+        self.stop_mesh();
+    }
+
+    /// Stops the audio links with every peer
+    fn stop_mesh(&self) {
+        for (id, _, _, _) in self.mesh.get_peers() {
+            self.mesh.remove_peer(id);
         }
     }
 
-    pub fn get_peers(&self) -> Vec<(u8, String)> {
-        let peers = self.audio_peers.lock().unwrap();
-        let mut result: Vec<(u8,String)> = Vec::new();
-        peers.iter().for_each(|(id, (username, _, _))| {
-            result.push((*id, username.clone()));
+    /// Drops a peer from the room, only works once we took over the room
+    /// # Arguments
+    /// * `id` - The peer to drop
+    /// * `ban` - Keep its address out of the room until we stop hosting it
+    /// # Returns
+    /// * `bool` - ```false``` if we are not the host or the peer is not in the room
+    pub fn kick(&self, id: PeerId, ban: bool) -> bool {
+        let promoted = self.promoted.lock().unwrap().clone();
+        if promoted.is_none() {
+            warn!("Only the host can drop peer {}", id);
+            return false;
+        }
+        promoted.unwrap().kick(id, ban)
+    }
+    pub fn send_opus(&self, opus_packet: Vec<u8>) {
+        let promoted = self.promoted.lock().unwrap().clone();
+        if let Some(server) = promoted {
+            server.send_opus(opus_packet);
+            return;
+        }
+        let relayed = self.mesh.send_opus(opus_packet);
+        if relayed.is_empty() {
+            return;
+        }
+        self.runtime.block_on(async {
+            for voice in relayed.iter() {
+                if let Err(e) = self.send_relayed(voice).await {
+                    debug!("Failed to relay a voice packet: {}", e);
+                }
+            }
         });
-        result
     }
 
-    pub fn change_peer_volume(&self, peer_id: u8, volume: u8){
-        let peers = self.audio_peers.lock().unwrap();
-        let peer = peers.get(&peer_id);
-        if peer.is_none(){
-            error!("Peer {} not found", peer_id);
+    pub fn get_peers(&self) -> Vec<(PeerId, String, Presence, bool)> {
+        self.mesh.get_peers()
+    }
+
+    /// Returns why the last audio link failed to start, once
+    pub fn take_audio_error(&self) -> Option<AudioError> {
+        self.mesh.take_audio_error()
+    }
+
+    pub fn change_peer_volume(&self, peer_id: PeerId, volume: u8){
+        self.mesh.change_peer_volume(peer_id, volume);
+    }
+
+    /// Sends a chat message through the host
+    /// # Arguments
+    /// * `target` - The peer to send it to, everyone in the room if ```None```
+    /// * `text` - The message
+    pub fn send_chat(&self, target: Option<PeerId>, text: String) {
+        let promoted = self.promoted.lock().unwrap().clone();
+        if let Some(server) = promoted {
+            server.send_chat(target, text);
+            return;
+        }
+        if !self.capabilities.lock().unwrap().contains(Capabilities::CHAT) {
+            warn!("Not sending a chat message, the host doesn't support chat");
+            return;
+        }
+        let try_chat = self.mesh.chat(target, text);
+        if try_chat.is_none() {
+            return;
+        }
+        let chat = try_chat.unwrap();
+        if let Err(e) = self.runtime.block_on(self.send(&chat)) {
+            error!("Failed to send chat message: {}", e);
+        }
+    }
+    pub fn get_chat(&self) -> Vec<ChatEntry> {
+        self.mesh.get_chat()
+    }
+
+    /// Changes our mute, deafen and away state and lets everyone in the room know
+    /// # Arguments
+    /// * `presence` - Our new state, its speaking flag is ignored
+    pub fn set_presence(&self, presence: Presence) {
+        let promoted = self.promoted.lock().unwrap().clone();
+        if let Some(server) = promoted {
+            server.set_presence(presence);
+            return;
+        }
+        let try_update = self.mesh.set_presence(presence);
+        if try_update.is_none() || !self.capabilities.lock().unwrap().contains(Capabilities::PRESENCE) {
             return;
         }
-        let (_, _, peer) = peer.unwrap();
-        peer.change_volume(volume);
+        if let Err(e) = self.runtime.block_on(self.send(&try_update.unwrap())) {
+            error!("Failed to send our presence: {}", e);
+        }
     }
 }
+
+/// Picks the member with the lowest id that can host the room
+/// # Arguments
+/// * `members` - Everyone left in the room
+/// * `unreachable` - Members that were elected but never answered
+fn elect(members: &HashMap<PeerId, Member>, unreachable: &[PeerId]) -> Option<Member> {
+    members
+        .values()
+        .filter(|member| !member.standby.is_empty() && !unreachable.contains(&member.id))
+        .min_by_key(|member| member.id)
+        .cloned()
+}
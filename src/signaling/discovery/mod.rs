@@ -0,0 +1,495 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use stunclient::StunClient;
+
+/// STUN servers asked when the user doesn't set any
+pub const DEFAULT_STUN_SERVERS: [&str; 2] = ["stun.l.google.com:19302", "stun1.l.google.com:19302"];
+/// Time each STUN server has to answer before the next one is asked
+pub const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+/// Port of the STUN servers given without one
+pub const STUN_PORT: u16 = 3478;
+
+/// How we find the address the other peers can reach us at
+#[derive(Debug, Clone, PartialEq)]
+pub enum Discovery {
+    /// Ask the STUN servers in order, the first one that answers wins. If none
+    /// answers the address of a local interface is used
+    Stun { servers: Vec<String>, timeout: Duration },
+    /// Use the address of a local interface without asking anyone, for peers in the same network
+    Lan,
+    /// Give the other peers this address, for a machine with a known public address
+    /// or with its ports forwarded by the router
+    Advertise { ip: IpAddr },
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Discovery::stun(DEFAULT_STUN_SERVERS.iter().map(|server| server.to_string()).collect())
+    }
+}
+
+impl Discovery {
+    /// Asks the given STUN servers with the default timeout
+    /// # Arguments
+    /// * `servers` - ```host:port``` of every server, in order
+    pub fn stun(servers: Vec<String>) -> Self {
+        Discovery::Stun { servers, timeout: STUN_TIMEOUT }
+    }
+
+    /// Parses a list of STUN servers separated by commas or spaces, ```STUN_PORT``` is
+    /// used for the servers given without a port
+    /// # Arguments
+    /// * `list` - The list, for example ```stun.example.org, [::1]:3478```
+    pub fn parse_servers(list: &str) -> Vec<String> {
+        list.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|server| !server.is_empty())
+            .map(|server| {
+                let has_port = match server.rsplit_once(':') {
+                    //Bare IPv6 addresses have colons but no brackets
+                    Some((host, port)) => port.parse::<u16>().is_ok() && (!host.contains(':') || host.ends_with(']')),
+                    None => false,
+                };
+                if has_port {
+                    server.to_string()
+                } else if server.contains(':') && !server.starts_with('[') {
+                    format!("[{}]:{}", server, STUN_PORT)
+                } else {
+                    format!("{}:{}", server, STUN_PORT)
+                }
+            })
+            .collect()
+    }
+}
+
+/// Where our sockets are bound, the address the other peers are given comes from
+/// the ```Discovery``` instead. By default every interface and any free port is used
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Binding {
+    /// Interface to listen on, every interface of both families if ```None```
+    pub ip: Option<IpAddr>,
+    /// Port of the signaling listener, a free one if 0
+    pub port: u16,
+    /// First and last port the audio sockets may take, a free one if ```None```
+    pub audio_ports: Option<(u16, u16)>,
+}
+
+impl Binding {
+    /// Parses a port range such as ```50000-50100```, a single port or an empty text for any port
+    /// # Arguments
+    /// * `range` - The range
+    pub fn parse_ports(range: &str) -> Result<Option<(u16, u16)>, String> {
+        let range = range.trim();
+        if range.is_empty() {
+            return Ok(None);
+        }
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let parse = |port: &str| port.trim().parse::<u16>().ok().filter(|port| *port != 0);
+        match (parse(first), parse(last)) {
+            (Some(first), Some(last)) if first <= last => Ok(Some((first, last))),
+            _ => Err(format!("invalid port range {}", range)),
+        }
+    }
+
+    /// Binds the signaling listener
+    /// # Errors
+    /// * `io::Error` - If the port is taken or the interface doesn't exist
+    pub fn listen(&self) -> io::Result<TcpListener> {
+        match self.ip {
+            Some(ip) => TcpListener::bind(SocketAddr::new(ip, self.port)),
+            //Takes IPv4 too on most systems, IPv4 alone is left for the ones without IPv6
+            None => TcpListener::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), self.port))
+                .or_else(|_| TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), self.port))),
+        }
+    }
+
+    /// Binds the sockets of an audio link, one per address family unless the interface is set
+    /// # Errors
+    /// * `io::Error` - If no socket can be bound
+    pub fn bind_audio(&self) -> io::Result<Vec<UdpSocket>> {
+        let ips = match self.ip {
+            Some(ip) => vec![ip],
+            None => vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED), IpAddr::V6(Ipv6Addr::UNSPECIFIED)],
+        };
+        let mut sockets = Vec::new();
+        let mut last_error = None;
+        for ip in ips {
+            match self.bind_udp(ip) {
+                Ok(socket) => sockets.push(socket),
+                Err(e) => {
+                    debug!("Can't bind an audio socket on {}: {}", ip, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) if sockets.is_empty() => Err(e),
+            _ => Ok(sockets),
+        }
+    }
+
+    /// Binds a UDP socket on the first free port of the audio range, starting from a
+    /// random one so the links of a call don't all try the same ports
+    /// # Arguments
+    /// * `ip` - The interface
+    fn bind_udp(&self, ip: IpAddr) -> io::Result<UdpSocket> {
+        let (first, last) = match self.audio_ports {
+            Some(range) => range,
+            None => return UdpSocket::bind(SocketAddr::new(ip, 0)),
+        };
+        let count = (last - first) as u32 + 1;
+        let start = rand::random::<u32>() % count;
+        for offset in 0..count {
+            let port = first + ((start + offset) % count) as u16;
+            if let Ok(socket) = UdpSocket::bind(SocketAddr::new(ip, port)) {
+                return Ok(socket);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::AddrInUse, format!("every port from {} to {} is taken", first, last)))
+    }
+}
+
+/// How a candidate reaches us, from the most to the least direct
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandidateKind {
+    /// The address of one of our interfaces
+    Host,
+    /// The address a STUN server sees our audio socket at, behind a NAT
+    ServerReflexive,
+    /// The signaling host, the voice goes through it with ```Voice``` messages
+    Relay,
+}
+
+impl CandidateKind {
+    /// Preference of the kind in the candidate priority, as suggested by RFC 8445
+    fn preference(self) -> u32 {
+        match self {
+            CandidateKind::Host => 126,
+            CandidateKind::ServerReflexive => 100,
+            CandidateKind::Relay => 0,
+        }
+    }
+}
+
+/// An address a peer may be reached at, every peer sends all of its candidates
+/// and the audio goes through the best pair that passes the connectivity checks
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    /// ```None``` for the relay
+    pub address: Option<SocketAddr>,
+    pub priority: u32,
+}
+
+impl Candidate {
+    /// Creates a candidate with the priority of RFC 8445, IPv6 goes before IPv4
+    /// # Arguments
+    /// * `kind` - How the candidate reaches us
+    /// * `address` - The address, ```None``` for the relay
+    pub fn new(kind: CandidateKind, address: Option<SocketAddr>) -> Self {
+        let local_preference = match address {
+            Some(SocketAddr::V6(_)) => 65535,
+            Some(SocketAddr::V4(_)) => 65534,
+            None => 0,
+        };
+        //A single component, the voice
+        let priority = (kind.preference() << 24) + (local_preference << 8) + 255;
+        Candidate { kind, address, priority }
+    }
+
+    /// The signaling host, always the last resort
+    pub fn relay() -> Self {
+        Candidate::new(CandidateKind::Relay, None)
+    }
+
+    /// Priority of a pair of candidates, both peers give the same pair the same
+    /// priority as long as they agree on who started the negotiation
+    /// # Arguments
+    /// * `local` - Our candidate priority
+    /// * `remote` - The remote candidate priority
+    /// * `initiator` - ```true``` if we sent the ```Announce```
+    pub fn pair_priority(local: u32, remote: u32, initiator: bool) -> u64 {
+        let (ours, theirs) = if initiator { (local, remote) } else { (remote, local) };
+        let (low, high) = (ours.min(theirs) as u64, ours.max(theirs) as u64);
+        (low << 32) + 2 * high + (ours > theirs) as u64
+    }
+}
+
+/// Finds the addresses we send to the other peers. The address of the signaling
+/// listeners is only looked up once, the audio candidates are gathered for every peer
+pub struct AddressResolver {
+    discovery: Discovery,
+    binding: Binding,
+    ip: Mutex<Option<IpAddr>>,
+    //Per family (IPv4, IPv6), set once no STUN server answered so the next peers don't wait for them again
+    unanswered: [AtomicBool; 2],
+}
+
+impl AddressResolver {
+    pub fn new(discovery: Discovery) -> Self {
+        AddressResolver {
+            discovery,
+            binding: Binding::default(),
+            ip: Mutex::new(None),
+            unanswered: [AtomicBool::new(false), AtomicBool::new(false)],
+        }
+    }
+
+    /// Binds our sockets somewhere else than every interface and any free port
+    /// # Arguments
+    /// * `binding` - Where to bind them
+    pub fn with_binding(mut self, binding: Binding) -> Self {
+        self.binding = binding;
+        self
+    }
+
+    pub fn discovery(&self) -> &Discovery {
+        &self.discovery
+    }
+
+    pub fn binding(&self) -> &Binding {
+        &self.binding
+    }
+
+    /// Returns the address the other peers reach a listener at, our address with the
+    /// port the listener is bound to
+    /// # Arguments
+    /// * `listener` - The listener
+    /// # Errors
+    /// * `io::Error` - If the listener is closed
+    pub fn advertise(&self, listener: &TcpListener) -> io::Result<String> {
+        let bound = listener.local_addr()?;
+        //Bound to a single interface, nobody can reach it at another address
+        let ip = if bound.ip().is_unspecified() { self.ip() } else { bound.ip() };
+        Ok(SocketAddr::new(ip, bound.port()).to_string())
+    }
+
+    /// Returns the address we can be reached at, looking it up the first time
+    pub fn ip(&self) -> IpAddr {
+        let mut cached = self.ip.lock().unwrap();
+        if let Some(ip) = *cached {
+            return ip;
+        }
+        let ip = match &self.discovery {
+            Discovery::Stun { servers, timeout } => match query_servers(servers, *timeout) {
+                Some(ip) => ip,
+                None => {
+                    warn!("No STUN server answered, using the address of a local interface");
+                    local_ip()
+                }
+            },
+            Discovery::Lan => local_ip(),
+            Discovery::Advertise { ip } => *ip,
+        };
+        info!("Our address is {}", ip);
+        *cached = Some(ip);
+        ip
+    }
+
+    /// Gathers the host and server reflexive candidates of the audio sockets, the
+    /// STUN servers are asked through the sockets themselves so a NAT maps them
+    /// the same way it will map the voice
+    /// # Arguments
+    /// * `sockets` - The audio sockets, made by ```Binding::bind_audio```
+    /// # Returns
+    /// * `Vec<Candidate>` - The candidates, best first. The relay is not part of them
+    pub fn candidates(&self, sockets: &[UdpSocket]) -> Vec<Candidate> {
+        let mut candidates = Vec::new();
+        for socket in sockets {
+            let try_local = socket.local_addr();
+            if let Err(e) = try_local {
+                warn!("Can't read the address of an audio socket: {}", e);
+                continue;
+            }
+            let local = try_local.unwrap();
+            let ipv6 = local.is_ipv6();
+            let try_ip = if local.ip().is_unspecified() { route_ip(ipv6) } else { Ok(local.ip()) };
+            let host = match try_ip {
+                Ok(ip) => SocketAddr::new(ip, local.port()),
+                Err(e) => {
+                    debug!("No {} interface: {}", if ipv6 { "IPv6" } else { "IPv4" }, e);
+                    continue;
+                }
+            };
+            candidates.push(Candidate::new(CandidateKind::Host, Some(host)));
+
+            let servers = match &self.discovery {
+                Discovery::Stun { servers, timeout } => Some((servers, *timeout)),
+                Discovery::Lan => None,
+                Discovery::Advertise { ip } => {
+                    //The forwarded port is taken to be the same one
+                    let advertised = SocketAddr::new(*ip, local.port());
+                    if ip.is_ipv6() == ipv6 && advertised != host {
+                        candidates.push(Candidate::new(CandidateKind::ServerReflexive, Some(advertised)));
+                    }
+                    None
+                }
+            };
+            let unanswered = &self.unanswered[ipv6 as usize];
+            if let Some((servers, timeout)) = servers.filter(|_| !unanswered.load(Ordering::Relaxed)) {
+                match query_servers_with(servers, socket, timeout) {
+                    Some(external) if external != host => {
+                        candidates.push(Candidate::new(CandidateKind::ServerReflexive, Some(external)))
+                    }
+                    Some(_) => {}
+                    None => {
+                        warn!("No STUN server answered over {}, only the host candidate is used", if ipv6 { "IPv6" } else { "IPv4" });
+                        unanswered.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+        candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.priority));
+        candidates
+    }
+}
+
+/// Asks the STUN servers in order until one answers
+/// # Arguments
+/// * `servers` - ```host:port``` of every server
+/// * `timeout` - Time each server has to answer
+fn query_servers(servers: &[String], timeout: Duration) -> Option<IpAddr> {
+    for server in servers {
+        match query(server, timeout) {
+            Ok(address) => return Some(address.ip()),
+            Err(e) => warn!("STUN server {} didn't answer: {}", server, e),
+        }
+    }
+    None
+}
+
+/// Asks the STUN servers in order through an audio socket until one answers
+/// # Arguments
+/// * `servers` - ```host:port``` of every server
+/// * `socket` - The audio socket, only the server addresses of its family are asked
+/// * `timeout` - Time each server has to answer
+fn query_servers_with(servers: &[String], socket: &UdpSocket, timeout: Duration) -> Option<SocketAddr> {
+    let ipv6 = socket.local_addr().ok()?.is_ipv6();
+    for server in servers {
+        let addresses = match server.to_socket_addrs() {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                warn!("Can't resolve STUN server {}: {}", server, e);
+                continue;
+            }
+        };
+        for address in addresses.filter(|address| address.is_ipv6() == ipv6) {
+            let mut client = StunClient::new(address);
+            client.set_software(Some("Savi"));
+            client.set_timeout(timeout);
+            match client.query_external_address(socket) {
+                Ok(external) => {
+                    debug!("STUN server {} at {} sees our audio socket as {}", server, address, external);
+                    return Some(external);
+                }
+                Err(e) => debug!("STUN server {} at {} didn't answer: {}", server, address, e),
+            }
+        }
+    }
+    None
+}
+
+/// Asks a STUN server which address it sees our requests coming from, over IPv6 if it has an IPv6 address
+/// # Arguments
+/// * `server` - ```host:port``` of the server
+/// * `timeout` - Time the server has to answer
+fn query(server: &str, timeout: Duration) -> io::Result<SocketAddr> {
+    let mut addresses: Vec<SocketAddr> = server.to_socket_addrs()?.collect();
+    addresses.sort_by_key(|address| !address.is_ipv6());
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "the server has no address");
+    for address in addresses {
+        let unspecified = if address.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+        let udp = UdpSocket::bind(unspecified)?;
+        let mut client = StunClient::new(address);
+        client.set_software(Some("Savi"));
+        client.set_timeout(timeout);
+        match client.query_external_address(&udp) {
+            Ok(external) => {
+                debug!("STUN server {} at {} sees us as {}", server, address, external);
+                return Ok(external);
+            }
+            Err(e) => last_error = io::Error::new(io::ErrorKind::Other, e.to_string()),
+        }
+    }
+    Err(last_error)
+}
+
+/// Returns the address of the interface used to reach other networks, IPv4 first since
+/// every local network has it. Nothing is sent, connecting a UDP socket only picks a route
+fn local_ip() -> IpAddr {
+    for ipv6 in [false, true] {
+        match route_ip(ipv6) {
+            Ok(ip) => return ip,
+            Err(e) => debug!("No {} route: {}", if ipv6 { "IPv6" } else { "IPv4" }, e),
+        }
+    }
+    warn!("No network interface is up, only this machine can reach us");
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+/// Returns the address of the interface used to reach other networks over IPv4 or IPv6
+/// # Arguments
+/// * `ipv6` - The family of the address
+fn route_ip(ipv6: bool) -> io::Result<IpAddr> {
+    let (unspecified, target) = if ipv6 { ("[::]:0", "[2001:db8::1]:9") } else { ("0.0.0.0:0", "192.0.2.1:9") };
+    let udp = UdpSocket::bind(unspecified)?;
+    udp.connect(target)?;
+    let ip = udp.local_addr()?.ip();
+    if ip.is_unspecified() {
+        return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "the route has no source address"));
+    }
+    Ok(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_list_of_stun_servers() {
+        assert_eq!(Discovery::parse_servers("stun.example.org, [::1]:3478"), vec!["stun.example.org:3478", "[::1]:3478"]);
+        assert_eq!(Discovery::parse_servers(" 192.0.2.1:19302 ::1,,"), vec!["192.0.2.1:19302", "[::1]:3478"]);
+        assert!(Discovery::parse_servers(" , ").is_empty());
+    }
+
+    #[test]
+    fn parses_an_audio_port_range() {
+        assert_eq!(Binding::parse_ports("50000-50100"), Ok(Some((50000, 50100))));
+        assert_eq!(Binding::parse_ports(" 50000 - 50000 "), Ok(Some((50000, 50000))));
+        assert_eq!(Binding::parse_ports("7700"), Ok(Some((7700, 7700))));
+        assert_eq!(Binding::parse_ports(""), Ok(None));
+    }
+
+    #[test]
+    fn refuses_an_invalid_port_range() {
+        for range in ["50100-50000", "0-100", "50000-", "-50000", "50000-70000", "ports", "1-2-3"] {
+            assert!(Binding::parse_ports(range).is_err(), "{} was accepted", range);
+        }
+    }
+
+    #[test]
+    fn both_peers_rank_the_pairs_the_same_way() {
+        let v6 = Candidate::new(CandidateKind::Host, Some("[::1]:5000".parse().unwrap())).priority;
+        let v4 = Candidate::new(CandidateKind::Host, Some("127.0.0.1:5000".parse().unwrap())).priority;
+        let reflexive = Candidate::new(CandidateKind::ServerReflexive, Some("203.0.113.9:5000".parse().unwrap())).priority;
+        let relay = Candidate::relay().priority;
+        assert!(v6 > v4 && v4 > reflexive && reflexive > relay);
+
+        for (ours, theirs) in [(v6, v4), (v4, reflexive), (reflexive, v6), (relay, v4)] {
+            assert_eq!(Candidate::pair_priority(ours, theirs, true), Candidate::pair_priority(theirs, ours, false));
+        }
+        let host = Candidate::pair_priority(v4, v4, true);
+        let mixed = Candidate::pair_priority(v4, reflexive, true);
+        let reflexive_pair = Candidate::pair_priority(reflexive, reflexive, true);
+        assert!(host > mixed && mixed > reflexive_pair && reflexive_pair > Candidate::pair_priority(relay, relay, true));
+        //Ties between mirrored pairs go to the one the initiator prefers
+        assert!(Candidate::pair_priority(v6, v4, true) > Candidate::pair_priority(v4, v6, true));
+    }
+}
@@ -0,0 +1,72 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::fmt;
+use std::io;
+
+use crate::aes::CryptoError;
+#[cfg(feature = "audio")]
+use crate::audio::AudioError;
+use crate::signaling::message::MessageError;
+
+/// Why hosting or joining a room failed, or why the call ended
+#[derive(Debug)]
+pub enum SignalingError {
+    /// We can't listen on the bind address or the async runtime can't start
+    Io(io::Error),
+    /// The room key is not valid
+    Crypto(CryptoError),
+    /// The host can't be reached or it didn't let us in
+    Message(MessageError),
+    /// Our audio links can't be set up
+    #[cfg(feature = "audio")]
+    Audio(AudioError),
+    /// The host didn't let us in in time
+    TimedOut,
+    /// The connection with the host was lost and nobody could take over the room
+    Disconnected,
+    /// The host dropped us from the room
+    Kicked,
+}
+
+impl fmt::Display for SignalingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignalingError::Io(e) => write!(f, "network error: {}", e),
+            SignalingError::Crypto(e) => write!(f, "invalid room key: {}", e),
+            SignalingError::Message(e) => write!(f, "{}", e),
+            #[cfg(feature = "audio")]
+            SignalingError::Audio(e) => write!(f, "{}", e),
+            SignalingError::TimedOut => write!(f, "the host didn't answer in time"),
+            SignalingError::Disconnected => write!(f, "the connection with the room was lost"),
+            SignalingError::Kicked => write!(f, "the host removed you from the room"),
+        }
+    }
+}
+
+impl std::error::Error for SignalingError {}
+
+impl From<io::Error> for SignalingError {
+    fn from(e: io::Error) -> Self {
+        SignalingError::Io(e)
+    }
+}
+
+impl From<CryptoError> for SignalingError {
+    fn from(e: CryptoError) -> Self {
+        SignalingError::Crypto(e)
+    }
+}
+
+impl From<MessageError> for SignalingError {
+    fn from(e: MessageError) -> Self {
+        SignalingError::Message(e)
+    }
+}
+
+#[cfg(feature = "audio")]
+impl From<AudioError> for SignalingError {
+    fn from(e: AudioError) -> Self {
+        SignalingError::Audio(e)
+    }
+}
@@ -0,0 +1,166 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the length prefix that goes before every frame
+pub const HEADER_SIZE: usize = 4;
+/// Biggest payload a single frame can carry, anything above is treated as a corrupt stream
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Serializes a payload into a frame.
+/// The frame is serialized as follows:
+/// <payload length 4 bytes big endian><payload variable size>
+/// # Arguments
+/// * `payload` - The bytes to frame
+/// # Errors
+/// * `std::io::Error` - If the payload is bigger than ```MAX_FRAME_SIZE```
+pub fn encode_frame(payload: &[u8]) -> Result<Vec<u8>, Error> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Frame of {} bytes exceeds the {} bytes limit", payload.len(), MAX_FRAME_SIZE),
+        ));
+    }
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Writes a payload as a single frame.
+/// The header and the payload go out in a single ```write_all``` so a frame is
+/// never interleaved with another one
+/// # Arguments
+/// * `writer` - The stream to write to
+/// * `payload` - The bytes to send
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> Result<(), Error> {
+    let frame = encode_frame(payload)?;
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+/// Reads exactly one frame from the stream, waiting for the rest of the frame if
+/// it arrives split across several TCP segments and leaving any coalesced bytes
+/// of the next frame untouched in the stream.
+/// # Arguments
+/// * `reader` - The stream to read from
+/// # Returns
+/// * `Some(Vec<u8>)` - The frame payload
+/// * `None` - If the stream was closed cleanly between two frames
+/// # Errors
+/// * `std::io::Error` - If the stream closed in the middle of a frame or the announced length is bigger than ```MAX_FRAME_SIZE```
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut header = [0; HEADER_SIZE];
+    let mut filled = 0;
+    while filled < HEADER_SIZE {
+        match reader.read(&mut header[filled..]).await {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Stream closed in the middle of a frame header"));
+            }
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    let size = u32::from_be_bytes(header) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Frame of {} bytes exceeds the {} bytes limit", size, MAX_FRAME_SIZE),
+        ));
+    }
+    let mut payload = vec![0; size];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    /// Hands out the stream one chunk per read, like TCP segments
+    struct ChunkedReader {
+        chunks: VecDeque<Vec<u8>>,
+    }
+
+    impl ChunkedReader {
+        fn new(chunks: Vec<Vec<u8>>) -> Self {
+            ChunkedReader { chunks: chunks.into() }
+        }
+    }
+
+    impl AsyncRead for ChunkedReader {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            if let Some(mut chunk) = self.chunks.pop_front() {
+                let n = chunk.len().min(buf.remaining());
+                buf.put_slice(&chunk[..n]);
+                if n < chunk.len() {
+                    self.chunks.push_front(chunk.split_off(n));
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_a_frame_split_across_reads() {
+        let frame = encode_frame(b"hello world").unwrap();
+        let chunks = vec![frame[..1].to_vec(), frame[1..3].to_vec(), frame[3..6].to_vec(), frame[6..].to_vec()];
+        let mut reader = ChunkedReader::new(chunks);
+
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(b"hello world".to_vec()));
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reads_two_frames_coalesced_in_one_read() {
+        let mut both = encode_frame(b"first").unwrap();
+        both.extend(encode_frame(b"second").unwrap());
+        let mut reader = ChunkedReader::new(vec![both]);
+
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(b"first".to_vec()));
+        assert_eq!(read_frame(&mut reader).await.unwrap(), Some(b"second".to_vec()));
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn round_trips_through_a_stream() {
+        let (mut client, mut server) = tokio::io::duplex(16);
+        let writer = tokio::spawn(async move {
+            write_frame(&mut client, &[7; 100]).await.unwrap();
+            write_frame(&mut client, b"").await.unwrap();
+        });
+
+        assert_eq!(read_frame(&mut server).await.unwrap(), Some(vec![7; 100]));
+        assert_eq!(read_frame(&mut server).await.unwrap(), Some(Vec::new()));
+        writer.await.unwrap();
+        assert_eq!(read_frame(&mut server).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_an_oversized_length_prefix() {
+        let header = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes().to_vec();
+        let mut reader = ChunkedReader::new(vec![header]);
+
+        let error = read_frame(&mut reader).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(encode_frame(&vec![0; MAX_FRAME_SIZE + 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_stream_closed_mid_frame() {
+        let frame = encode_frame(b"cut short").unwrap();
+        let mut header = ChunkedReader::new(vec![frame[..2].to_vec()]);
+        let mut payload = ChunkedReader::new(vec![frame[..6].to_vec()]);
+
+        assert_eq!(read_frame(&mut header).await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(read_frame(&mut payload).await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,487 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::aes::AES;
+use crate::signaling::discovery::Candidate;
+use crate::signaling::message::SignalingMessage;
+use crate::signaling::protocol::{Capabilities, Protocol, MIN_PROTOCOL_VERSION};
+
+/// Time a peer has to answer each step of the negotiation
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Size of the random salt each peer adds to the audio keys of the link
+pub const SALT_SIZE: usize = 16;
+
+/// The negotiation between two peers goes as follows:
+/// ```text
+/// initiator                responder
+///     | ------ Announce ------> |   Announced / Acked
+///     | <------- Ack ---------- |
+///     | -------- Ok ----------> |   Confirmed / Connected
+///     | <------- Ko ----------- |   Connected / Connected
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HandshakeState {
+    /// Initiator, waiting for the ```Ack```
+    Announced,
+    /// Responder, waiting for the ```Ok```
+    Acked,
+    /// Initiator, waiting for the ```Ko```
+    Confirmed,
+    /// Both sides know each other, the audio connection is up
+    Connected,
+    /// The remote peer took too long to answer
+    TimedOut,
+}
+
+/// What the owner of the handshake has to do after feeding it a message
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeAction {
+    /// Send a message to the remote peer
+    Send(SignalingMessage),
+    /// Start the connectivity checks with the candidates of the remote peer
+    Connect { candidates: Vec<Candidate> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeError {
+    /// The message is not valid in the current state
+    Unexpected { state: HandshakeState, message: SignalingMessage },
+    /// The handshake already timed out
+    Expired,
+    /// The remote peer speaks a protocol version older than the oldest one we can talk to,
+    /// or it lacks a feature the audio link needs
+    Incompatible { protocol: Protocol },
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Unexpected { state, message } => write!(f, "unexpected {:?} while {:?}", message, state),
+            HandshakeError::Expired => write!(f, "handshake timed out"),
+            HandshakeError::Incompatible { protocol } if protocol.version < MIN_PROTOCOL_VERSION => write!(
+                f,
+                "the peer speaks protocol version {} but we need at least version {}",
+                protocol.version, MIN_PROTOCOL_VERSION
+            ),
+            HandshakeError::Incompatible { protocol } => write!(
+                f,
+                "the peer supports {} but we need {}",
+                protocol.capabilities, Capabilities::REQUIRED
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Per-peer negotiation state machine.
+/// It doesn't own any socket, every step returns the actions the caller must perform
+pub struct Handshake {
+    state: HandshakeState,
+    initiator: bool,
+    local_username: String,
+    local_candidates: Vec<Candidate>,
+    remote_username: String,
+    remote_candidates: Vec<Candidate>,
+    //Makes the audio keys of every link unique, even with the same peer ids
+    local_salt: [u8; SALT_SIZE],
+    remote_salt: [u8; SALT_SIZE],
+    //Features both peers support, empty until the initiator gets the ```Ack```
+    capabilities: Capabilities,
+    timeout: Duration,
+    deadline: Instant,
+}
+
+impl Handshake {
+    /// Starts a negotiation as the initiator
+    /// # Arguments
+    /// * `room` - The room both peers are in
+    /// * `username` - Our username
+    /// * `candidates` - Our address candidates
+    /// * `now` - The current time
+    /// * `timeout` - Time the remote peer has to answer each step
+    /// # Returns
+    /// * `(Handshake, SignalingMessage)` - The handshake and the ```Announce``` to send
+    pub fn initiate(room: String, username: String, candidates: Vec<Candidate>, now: Instant, timeout: Duration) -> (Self, SignalingMessage) {
+        let salt = rand::random();
+        let announce = SignalingMessage::Announce {
+            room,
+            username: username.clone(),
+            candidates: candidates.clone(),
+            protocol: Protocol::local(),
+            salt,
+        };
+        let handshake = Handshake {
+            state: HandshakeState::Announced,
+            initiator: true,
+            local_username: username,
+            local_candidates: candidates,
+            remote_username: String::new(),
+            remote_candidates: Vec::new(),
+            local_salt: salt,
+            remote_salt: [0; SALT_SIZE],
+            capabilities: Capabilities::NONE,
+            timeout,
+            deadline: now + timeout,
+        };
+        (handshake, announce)
+    }
+
+    /// Answers an ```Announce``` as the responder
+    /// # Arguments
+    /// * `username` - Our username
+    /// * `candidates` - Our address candidates
+    /// * `remote_username` - The username in the ```Announce```
+    /// * `remote_candidates` - The address candidates in the ```Announce```
+    /// * `remote_protocol` - The protocol in the ```Announce```
+    /// * `remote_salt` - The salt in the ```Announce```
+    /// * `now` - The current time
+    /// * `timeout` - Time the remote peer has to answer each step
+    /// # Returns
+    /// * `(Handshake, SignalingMessage)` - The handshake and the ```Ack``` to send
+    /// # Errors
+    /// * `HandshakeError::Incompatible` - If we can't set up an audio link with the remote peer, it doesn't get an answer
+    #[allow(clippy::too_many_arguments)]
+    pub fn respond(
+        username: String,
+        candidates: Vec<Candidate>,
+        remote_username: String,
+        remote_candidates: Vec<Candidate>,
+        remote_protocol: Protocol,
+        remote_salt: [u8; SALT_SIZE],
+        now: Instant,
+        timeout: Duration,
+    ) -> Result<(Self, SignalingMessage), HandshakeError> {
+        let protocol = Protocol::local();
+        let capabilities = negotiate(&remote_protocol)?;
+        let salt = rand::random();
+        let ack = SignalingMessage::Ack {
+            username: username.clone(),
+            candidates: candidates.clone(),
+            protocol,
+            salt,
+        };
+        let handshake = Handshake {
+            state: HandshakeState::Acked,
+            initiator: false,
+            local_username: username,
+            local_candidates: candidates,
+            remote_username,
+            remote_candidates,
+            local_salt: salt,
+            remote_salt,
+            capabilities,
+            timeout,
+            deadline: now + timeout,
+        };
+        Ok((handshake, ack))
+    }
+
+    /// Advances the negotiation with a message from the remote peer
+    /// # Arguments
+    /// * `message` - The message sent by the remote peer
+    /// * `now` - The current time
+    /// # Errors
+    /// * `HandshakeError::Unexpected` - If the message doesn't belong to the current step, the state is left untouched
+    /// * `HandshakeError::Expired` - If the handshake already timed out
+    /// * `HandshakeError::Incompatible` - If the ```Ack``` comes from a peer we can't talk to, the state is left untouched
+    pub fn handle(&mut self, message: SignalingMessage, now: Instant) -> Result<Vec<HandshakeAction>, HandshakeError> {
+        if self.state == HandshakeState::TimedOut {
+            return Err(HandshakeError::Expired);
+        }
+        match (self.state, message) {
+            (HandshakeState::Announced, SignalingMessage::Ack { username, candidates, protocol, salt }) => {
+                self.capabilities = negotiate(&protocol)?;
+                self.remote_username = username;
+                self.remote_candidates = candidates;
+                self.remote_salt = salt;
+                self.state = HandshakeState::Confirmed;
+                self.deadline = now + self.timeout;
+                Ok(vec![HandshakeAction::Send(SignalingMessage::Ok)])
+            }
+            (HandshakeState::Acked, SignalingMessage::Ok) => {
+                self.state = HandshakeState::Connected;
+                Ok(vec![
+                    HandshakeAction::Send(SignalingMessage::Ko),
+                    HandshakeAction::Connect { candidates: self.remote_candidates.clone() },
+                ])
+            }
+            (HandshakeState::Confirmed, SignalingMessage::Ko) => {
+                self.state = HandshakeState::Connected;
+                Ok(vec![HandshakeAction::Connect { candidates: self.remote_candidates.clone() }])
+            }
+            (state, message) => Err(HandshakeError::Unexpected { state, message }),
+        }
+    }
+
+    /// Marks the handshake as timed out if the remote peer missed the deadline
+    /// # Arguments
+    /// * `now` - The current time
+    /// # Returns
+    /// * `bool` - ```true``` if the handshake is (now) timed out
+    pub fn check_timeout(&mut self, now: Instant) -> bool {
+        let pending = self.state != HandshakeState::Connected && self.state != HandshakeState::TimedOut;
+        if pending && now >= self.deadline {
+            self.state = HandshakeState::TimedOut;
+        }
+        self.state == HandshakeState::TimedOut
+    }
+
+    pub fn state(&self) -> HandshakeState {
+        self.state
+    }
+
+    pub fn local_username(&self) -> &str {
+        &self.local_username
+    }
+
+    pub fn local_candidates(&self) -> &[Candidate] {
+        &self.local_candidates
+    }
+
+    /// The remote username, empty until the initiator gets the ```Ack```
+    pub fn remote_username(&self) -> &str {
+        &self.remote_username
+    }
+
+    /// The remote address candidates, empty until the initiator gets the ```Ack```
+    pub fn remote_candidates(&self) -> &[Candidate] {
+        &self.remote_candidates
+    }
+
+    /// ```true``` if we sent the ```Announce```, both peers need to agree on it to rank the candidate pairs the same way
+    pub fn is_initiator(&self) -> bool {
+        self.initiator
+    }
+
+    /// The features both peers support, empty until the initiator gets the ```Ack```
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Derives the keys of the audio link from the room key and the salts of both peers.
+    /// Each direction gets its own key so both peers can number their packets from zero
+    /// # Arguments
+    /// * `cipher` - The room cipher
+    /// # Returns
+    /// * `(AES, AES)` - The key we encrypt our voice with and the key the remote peer encrypts its voice with
+    pub fn audio_keys(&self, cipher: &AES) -> (AES, AES) {
+        let sending = cipher.derive(&audio_context(&self.local_salt, &self.remote_salt));
+        let receiving = cipher.derive(&audio_context(&self.remote_salt, &self.local_salt));
+        (sending, receiving)
+    }
+}
+
+/// Checks we can set up an audio link with a remote peer
+/// # Arguments
+/// * `remote` - The protocol the remote peer sent
+/// # Returns
+/// * `Capabilities` - The features both peers support
+/// # Errors
+/// * `HandshakeError::Incompatible` - If the remote version is too old or it lacks a required feature
+fn negotiate(remote: &Protocol) -> Result<Capabilities, HandshakeError> {
+    let incompatible = HandshakeError::Incompatible { protocol: *remote };
+    let capabilities = Protocol::local().negotiate(remote).map_err(|_| incompatible.clone())?;
+    if !capabilities.contains(Capabilities::REQUIRED) {
+        return Err(incompatible);
+    }
+    Ok(capabilities)
+}
+
+/// What the audio key of a direction is derived for
+/// # Arguments
+/// * `sender` - The salt of the peer that encrypts with the key
+/// * `receiver` - The salt of the other peer
+fn audio_context(sender: &[u8; SALT_SIZE], receiver: &[u8; SALT_SIZE]) -> Vec<u8> {
+    let mut context = b"savi audio".to_vec();
+    context.extend_from_slice(sender);
+    context.extend_from_slice(receiver);
+    context
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signaling::discovery::CandidateKind;
+    use crate::signaling::protocol::PROTOCOL_VERSION;
+
+    fn candidates(port: u16) -> Vec<Candidate> {
+        vec![Candidate::new(CandidateKind::Host, Some(([127, 0, 0, 1], port).into()))]
+    }
+
+    /// Starts a handshake as alice
+    fn initiate(now: Instant) -> (Handshake, SignalingMessage) {
+        Handshake::initiate("lobby".to_string(), "alice".to_string(), candidates(5000), now, DEFAULT_TIMEOUT)
+    }
+
+    /// Gets alice's announce answered by bob, returns both sides and bob's ```Ack```
+    fn exchange(now: Instant) -> (Handshake, Handshake, SignalingMessage) {
+        let (initiator, announce) = initiate(now);
+        let (responder, ack) = respond_to(announce, None, now).unwrap();
+        (initiator, responder, ack)
+    }
+
+    /// Answers an ```Announce``` the way the mesh does
+    fn respond_to(announce: SignalingMessage, protocol: Option<Protocol>, now: Instant) -> Result<(Handshake, SignalingMessage), HandshakeError> {
+        match announce {
+            SignalingMessage::Announce { username, candidates: remote_candidates, protocol: announced, salt, .. } => Handshake::respond(
+                "bob".to_string(),
+                candidates(6000),
+                username,
+                remote_candidates,
+                protocol.unwrap_or(announced),
+                salt,
+                now,
+                DEFAULT_TIMEOUT,
+            ),
+            message => panic!("expected an Announce, got {:?}", message),
+        }
+    }
+
+    fn ack_with(ack: SignalingMessage, protocol: Protocol) -> SignalingMessage {
+        match ack {
+            SignalingMessage::Ack { username, candidates, salt, .. } => SignalingMessage::Ack { username, candidates, protocol, salt },
+            message => panic!("expected an Ack, got {:?}", message),
+        }
+    }
+
+    fn old_protocol() -> Protocol {
+        Protocol { version: MIN_PROTOCOL_VERSION - 1, capabilities: Capabilities::local() }
+    }
+
+    #[test]
+    fn both_roles_reach_connected() {
+        let now = Instant::now();
+        let (mut initiator, announce) = initiate(now);
+        assert_eq!(initiator.state(), HandshakeState::Announced);
+        assert!(initiator.is_initiator());
+
+        let (mut responder, ack) = respond_to(announce, None, now).unwrap();
+        assert_eq!(responder.state(), HandshakeState::Acked);
+        assert!(!responder.is_initiator());
+        assert_eq!(responder.remote_username(), "alice");
+
+        let actions = initiator.handle(ack, now).unwrap();
+        assert_eq!(actions, vec![HandshakeAction::Send(SignalingMessage::Ok)]);
+        assert_eq!(initiator.state(), HandshakeState::Confirmed);
+        assert_eq!(initiator.remote_username(), "bob");
+
+        let actions = responder.handle(SignalingMessage::Ok, now).unwrap();
+        assert_eq!(
+            actions,
+            vec![HandshakeAction::Send(SignalingMessage::Ko), HandshakeAction::Connect { candidates: candidates(5000) }]
+        );
+        assert_eq!(responder.state(), HandshakeState::Connected);
+
+        let actions = initiator.handle(SignalingMessage::Ko, now).unwrap();
+        assert_eq!(actions, vec![HandshakeAction::Connect { candidates: candidates(6000) }]);
+        assert_eq!(initiator.state(), HandshakeState::Connected);
+
+        assert_eq!(initiator.capabilities(), Capabilities::local());
+        assert_eq!(responder.capabilities(), Capabilities::local());
+    }
+
+    #[test]
+    fn both_roles_agree_on_the_audio_keys() {
+        let now = Instant::now();
+        let room = AES::new(None).unwrap();
+        let (mut initiator, responder, ack) = exchange(now);
+        initiator.handle(ack, now).unwrap();
+
+        let (initiator_sending, initiator_receiving) = initiator.audio_keys(&room);
+        let (responder_sending, responder_receiving) = responder.audio_keys(&room);
+        assert_eq!(initiator_sending.get_key(), responder_receiving.get_key());
+        assert_eq!(responder_sending.get_key(), initiator_receiving.get_key());
+        assert_ne!(initiator_sending.get_key(), initiator_receiving.get_key());
+    }
+
+    #[test]
+    fn out_of_order_messages_leave_the_state_untouched() {
+        let now = Instant::now();
+        let (mut initiator, mut responder, ack) = exchange(now);
+
+        for message in [SignalingMessage::Ok, SignalingMessage::Ko] {
+            let error = initiator.handle(message.clone(), now).unwrap_err();
+            assert_eq!(error, HandshakeError::Unexpected { state: HandshakeState::Announced, message });
+            assert_eq!(initiator.state(), HandshakeState::Announced);
+        }
+        for message in [ack.clone(), SignalingMessage::Ko] {
+            let error = responder.handle(message.clone(), now).unwrap_err();
+            assert_eq!(error, HandshakeError::Unexpected { state: HandshakeState::Acked, message });
+            assert_eq!(responder.state(), HandshakeState::Acked);
+        }
+
+        initiator.handle(ack.clone(), now).unwrap();
+        let error = initiator.handle(ack.clone(), now).unwrap_err();
+        assert_eq!(error, HandshakeError::Unexpected { state: HandshakeState::Confirmed, message: ack });
+        assert_eq!(initiator.state(), HandshakeState::Confirmed);
+
+        responder.handle(SignalingMessage::Ok, now).unwrap();
+        let error = responder.handle(SignalingMessage::Ok, now).unwrap_err();
+        assert_eq!(error, HandshakeError::Unexpected { state: HandshakeState::Connected, message: SignalingMessage::Ok });
+        assert_eq!(responder.state(), HandshakeState::Connected);
+    }
+
+    #[test]
+    fn responder_refuses_an_incompatible_initiator() {
+        let now = Instant::now();
+        let (_, announce) = initiate(now);
+        let old = old_protocol();
+        let error = respond_to(announce.clone(), Some(old), now).err().unwrap();
+        assert_eq!(error, HandshakeError::Incompatible { protocol: old });
+
+        let no_opus = Protocol { version: PROTOCOL_VERSION, capabilities: Capabilities::AES_GCM_SIV };
+        let error = respond_to(announce, Some(no_opus), now).err().unwrap();
+        assert_eq!(error, HandshakeError::Incompatible { protocol: no_opus });
+    }
+
+    #[test]
+    fn initiator_refuses_an_incompatible_responder() {
+        let now = Instant::now();
+        let (mut initiator, _, ack) = exchange(now);
+
+        let no_aes = Protocol { version: PROTOCOL_VERSION, capabilities: Capabilities::OPUS | Capabilities::CHAT };
+        for protocol in [old_protocol(), no_aes] {
+            let error = initiator.handle(ack_with(ack.clone(), protocol), now).unwrap_err();
+            assert_eq!(error, HandshakeError::Incompatible { protocol });
+            assert_eq!(initiator.state(), HandshakeState::Announced);
+            assert_eq!(initiator.remote_username(), "");
+        }
+
+        let chat_only = Protocol { version: PROTOCOL_VERSION, capabilities: Capabilities::REQUIRED | Capabilities::CHAT };
+        initiator.handle(ack_with(ack, chat_only), now).unwrap();
+        assert_eq!(initiator.capabilities(), Capabilities::REQUIRED | Capabilities::CHAT);
+    }
+
+    #[test]
+    fn a_silent_peer_times_out() {
+        let now = Instant::now();
+        let (mut initiator, mut responder, ack) = exchange(now);
+
+        assert!(!initiator.check_timeout(now + DEFAULT_TIMEOUT - Duration::from_millis(1)));
+        assert_eq!(initiator.state(), HandshakeState::Announced);
+        assert!(initiator.check_timeout(now + DEFAULT_TIMEOUT));
+        assert_eq!(initiator.state(), HandshakeState::TimedOut);
+        assert_eq!(initiator.handle(ack, now + DEFAULT_TIMEOUT), Err(HandshakeError::Expired));
+        assert_eq!(initiator.state(), HandshakeState::TimedOut);
+
+        responder.handle(SignalingMessage::Ok, now).unwrap();
+        assert!(!responder.check_timeout(now + DEFAULT_TIMEOUT * 2));
+        assert_eq!(responder.state(), HandshakeState::Connected);
+    }
+
+    #[test]
+    fn every_step_gets_a_fresh_deadline() {
+        let now = Instant::now();
+        let (mut initiator, _, ack) = exchange(now);
+
+        let later = now + DEFAULT_TIMEOUT / 2;
+        initiator.handle(ack, later).unwrap();
+        assert!(!initiator.check_timeout(now + DEFAULT_TIMEOUT));
+        assert!(initiator.check_timeout(later + DEFAULT_TIMEOUT));
+        assert_eq!(initiator.state(), HandshakeState::TimedOut);
+    }
+}
@@ -0,0 +1,29 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Time between two pings of the server
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+/// Time without hearing from the other side before the connection is considered dead
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Keepalive settings of the signaling connections.
+/// The server pings every connection each ```interval``` and drops the ones it
+/// didn't hear from within ```timeout```, the clients drop the connection if the
+/// server goes quiet for ```timeout```
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: DEFAULT_INTERVAL,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
@@ -0,0 +1,116 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::collections::BTreeSet;
+
+use crate::signaling::{PeerId, BROADCAST, HOST_ID};
+
+/// Hands out peer ids for a room.
+/// Ids of peers that left are given back to new peers, lowest first, and an id
+/// is never given to two live peers at the same time
+pub struct IdAllocator {
+    in_use: BTreeSet<PeerId>,
+}
+
+impl IdAllocator {
+    /// Creates an allocator with only ```HOST_ID``` and ```BROADCAST``` taken
+    pub fn new() -> Self {
+        IdAllocator {
+            in_use: BTreeSet::from([HOST_ID, BROADCAST]),
+        }
+    }
+
+    /// Takes the lowest free id
+    /// # Returns
+    /// * `Some(PeerId)` - The new id
+    /// * `None` - If every id is taken
+    pub fn allocate(&mut self) -> Option<PeerId> {
+        let mut candidate: PeerId = 0;
+        for id in self.in_use.iter() {
+            if *id != candidate {
+                break;
+            }
+            candidate = candidate.checked_add(1)?;
+        }
+        self.in_use.insert(candidate);
+        Some(candidate)
+    }
+
+    /// Takes a specific id, used to keep the ids of the peers after a host migration
+    /// # Returns
+    /// * `bool` - ```false``` if the id was already taken
+    pub fn reserve(&mut self, id: PeerId) -> bool {
+        self.in_use.insert(id)
+    }
+
+    /// Gives an id back so it can be reused
+    pub fn release(&mut self, id: PeerId) {
+        if id != HOST_ID && id != BROADCAST {
+            self.in_use.remove(&id);
+        }
+    }
+}
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_out_the_lowest_free_id() {
+        let mut ids = IdAllocator::new();
+        assert_eq!(ids.allocate(), Some(1));
+        assert_eq!(ids.allocate(), Some(2));
+        assert_eq!(ids.allocate(), Some(3));
+    }
+
+    #[test]
+    fn reuses_released_ids() {
+        let mut ids = IdAllocator::new();
+        for _ in 0..4 {
+            ids.allocate();
+        }
+        ids.release(2);
+        ids.release(3);
+        assert_eq!(ids.allocate(), Some(2));
+        assert_eq!(ids.allocate(), Some(3));
+        assert_eq!(ids.allocate(), Some(5));
+    }
+
+    #[test]
+    fn never_hands_out_the_host_or_broadcast_ids() {
+        let mut ids = IdAllocator::new();
+        ids.release(HOST_ID);
+        ids.release(BROADCAST);
+        assert_eq!(ids.allocate(), Some(1));
+        assert!(!ids.reserve(HOST_ID));
+        assert!(!ids.reserve(BROADCAST));
+    }
+
+    #[test]
+    fn runs_out_at_broadcast() {
+        let mut ids = IdAllocator::new();
+        for id in 1..BROADCAST {
+            assert!(ids.reserve(id));
+        }
+        assert_eq!(ids.allocate(), None);
+
+        ids.release(BROADCAST - 1);
+        assert_eq!(ids.allocate(), Some(BROADCAST - 1));
+        assert_eq!(ids.allocate(), None);
+    }
+
+    #[test]
+    fn reserve_refuses_taken_ids() {
+        let mut ids = IdAllocator::new();
+        assert!(ids.reserve(7));
+        assert!(!ids.reserve(7));
+        ids.release(7);
+        assert!(ids.reserve(7));
+    }
+}
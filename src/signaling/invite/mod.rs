@@ -0,0 +1,237 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::{Host, Url};
+
+use crate::aes::AES;
+use crate::signaling::server::DEFAULT_ROOM;
+use crate::signaling::transport;
+
+/// Scheme of the invite links
+pub const SCHEME: &str = "savi";
+/// Scheme of the invite links to servers reached over WebSocket
+pub const WS_SCHEME: &str = "savi+ws";
+/// Scheme of the invite links to servers reached over secure WebSocket
+pub const WSS_SCHEME: &str = "savi+wss";
+
+/// Everything needed to join a room, shared as a link:
+/// savi://<host>:<port>/<room>?key=<room key>&expires=<unix time>
+/// Servers reached over WebSocket use the ```savi+ws``` and ```savi+wss``` schemes,
+/// the WebSocket path goes in a ```path``` parameter.
+/// The expiry is only checked by the invited client, anyone holding the
+/// invite holds the room key anyway
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invite {
+    /// The signaling server address, ```host:port``` or a ```ws://```/```wss://``` URL
+    pub address: String,
+    pub key: String,
+    pub room: String,
+    /// Seconds since the unix epoch after which the invite is no longer valid
+    pub expires: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InviteError {
+    /// Not a URL at all
+    Malformed(url::ParseError),
+    /// A URL that is not a ```savi://``` one
+    WrongScheme(String),
+    /// The host or the port is missing
+    MissingAddress,
+    MissingKey,
+    /// The key is not a base64 AES-256 key
+    InvalidKey,
+    /// The ```expires``` parameter is not a unix time
+    BadExpiry(String),
+    Expired,
+}
+
+impl fmt::Display for InviteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InviteError::Malformed(e) => write!(f, "malformed invite: {}", e),
+            InviteError::WrongScheme(scheme) => write!(f, "not a {} invite, the scheme is {}", SCHEME, scheme),
+            InviteError::MissingAddress => write!(f, "the invite has no server address"),
+            InviteError::MissingKey => write!(f, "the invite has no room key"),
+            InviteError::InvalidKey => write!(f, "the room key of the invite is not valid"),
+            InviteError::BadExpiry(expires) => write!(f, "invalid invite expiry {}", expires),
+            InviteError::Expired => write!(f, "the invite expired"),
+        }
+    }
+}
+
+impl std::error::Error for InviteError {}
+
+impl Invite {
+    /// Creates an invite that never expires
+    /// # Arguments
+    /// * `address` - The signaling server address, ```host:port``` or a ```ws://```/```wss://``` URL
+    /// * `key` - The room key
+    /// * `room` - The room name
+    pub fn new(address: String, key: String, room: String) -> Self {
+        Invite { address, key, room, expires: None }
+    }
+
+    /// Makes the invite expire after some time from now
+    pub fn expiring_in(mut self, lifetime: Duration) -> Self {
+        self.expires = Some(unix_now() + lifetime.as_secs());
+        self
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires.map(|expires| unix_now() >= expires).unwrap_or(false)
+    }
+
+    /// Writes the invite as a ```savi://``` link
+    /// # Errors
+    /// * `InviteError::Malformed` - If the address is not a valid ```host:port``` or WebSocket URL
+    pub fn to_uri(&self) -> Result<String, InviteError> {
+        let room = urlencoding::encode(&self.room);
+        let (base, path) = if transport::is_websocket(&self.address) {
+            let server = Url::parse(&self.address).map_err(InviteError::Malformed)?;
+            let scheme = if server.scheme() == "wss" { WSS_SCHEME } else { WS_SCHEME };
+            let host = server.host_str().ok_or(InviteError::MissingAddress)?;
+            let port = server.port().map(|port| format!(":{}", port)).unwrap_or_default();
+            let path = Some(server.path().to_string()).filter(|path| path != "/");
+            (format!("{}://{}{}/{}", scheme, host, port, room), path)
+        } else {
+            (format!("{}://{}/{}", SCHEME, self.address, room), None)
+        };
+        let mut uri = Url::parse(&base).map_err(InviteError::Malformed)?;
+        {
+            let mut query = uri.query_pairs_mut();
+            query.append_pair("key", &self.key);
+            if let Some(path) = path {
+                query.append_pair("path", &path);
+            }
+            if let Some(expires) = self.expires {
+                query.append_pair("expires", &expires.to_string());
+            }
+        }
+        Ok(uri.to_string())
+    }
+
+    /// Reads a ```savi://``` link, expired invites are refused
+    /// # Arguments
+    /// * `uri` - The link, surrounding whitespace is ignored
+    pub fn parse(uri: &str) -> Result<Self, InviteError> {
+        let uri = Url::parse(uri.trim()).map_err(InviteError::Malformed)?;
+        let websocket = match uri.scheme() {
+            SCHEME => None,
+            WS_SCHEME => Some("ws"),
+            WSS_SCHEME => Some("wss"),
+            scheme => return Err(InviteError::WrongScheme(scheme.to_string())),
+        };
+        let host = match uri.host() {
+            Some(Host::Ipv6(ip)) => format!("[{}]", ip),
+            Some(host) => host.to_string(),
+            None => return Err(InviteError::MissingAddress),
+        };
+        //WebSocket servers can be on the default HTTP ports
+        let port = match (uri.port(), websocket) {
+            (Some(port), _) => format!(":{}", port),
+            (None, Some(_)) => String::new(),
+            (None, None) => return Err(InviteError::MissingAddress),
+        };
+        let path = uri.path().trim_start_matches('/');
+        let room = match urlencoding::decode(path) {
+            Ok(room) if !room.is_empty() => room.into_owned(),
+            _ => DEFAULT_ROOM.to_string(),
+        };
+
+        let mut key = None;
+        let mut expires = None;
+        let mut path = String::new();
+        for (name, value) in uri.query_pairs() {
+            match name.as_ref() {
+                "key" => key = Some(value.into_owned()),
+                "path" => path = value.into_owned(),
+                "expires" => expires = Some(value.parse::<u64>().map_err(|_| InviteError::BadExpiry(value.into_owned()))?),
+                _ => {}
+            }
+        }
+        let address = match websocket {
+            Some(scheme) => format!("{}://{}{}/{}", scheme, host, port, path.trim_start_matches('/')),
+            None => format!("{}{}", host, port),
+        };
+        let key = key.filter(|key| !key.is_empty()).ok_or(InviteError::MissingKey)?;
+        AES::new(Some(key.clone())).map_err(|_| InviteError::InvalidKey)?;
+        let invite = Invite { address, key, room, expires };
+        if invite.is_expired() {
+            return Err(InviteError::Expired);
+        }
+        Ok(invite)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> String {
+        AES::new(None).unwrap().get_key().clone()
+    }
+
+    fn round_trip(invite: Invite) {
+        assert_eq!(Invite::parse(&invite.to_uri().unwrap()), Ok(invite));
+    }
+
+    #[test]
+    fn round_trips_every_scheme() {
+        let invite = Invite::new("203.0.113.9:7700".to_string(), key(), "team room".to_string());
+        assert!(invite.to_uri().unwrap().starts_with("savi://203.0.113.9:7700/team%20room?key="));
+        round_trip(invite.expiring_in(Duration::from_secs(60)));
+
+        let ws = Invite::new("ws://example.org:7701/savi".to_string(), key(), "lobby".to_string());
+        assert!(ws.to_uri().unwrap().starts_with("savi+ws://example.org:7701/lobby?"));
+        round_trip(ws);
+
+        let wss = Invite::new("wss://example.org/savi/signal".to_string(), key(), "lobby".to_string());
+        assert!(wss.to_uri().unwrap().starts_with("savi+wss://example.org/lobby?"));
+        round_trip(wss);
+    }
+
+    #[test]
+    fn keeps_the_brackets_of_an_ipv6_host() {
+        let invite = Invite::new("[2001:db8::1]:7700".to_string(), key(), "lobby".to_string());
+        assert!(invite.to_uri().unwrap().starts_with("savi://[2001:db8::1]:7700/lobby?"));
+        round_trip(invite);
+    }
+
+    #[test]
+    fn refuses_an_expired_invite() {
+        let mut invite = Invite::new("203.0.113.9:7700".to_string(), key(), "lobby".to_string());
+        invite.expires = Some(unix_now() - 1);
+
+        assert_eq!(Invite::parse(&invite.to_uri().unwrap()), Err(InviteError::Expired));
+        assert_eq!(Invite::parse("savi://203.0.113.9:7700/lobby?key=a&expires=soon"), Err(InviteError::BadExpiry("soon".to_string())));
+    }
+
+    #[test]
+    fn refuses_a_missing_or_invalid_key() {
+        assert_eq!(Invite::parse("savi://203.0.113.9:7700/lobby"), Err(InviteError::MissingKey));
+        assert_eq!(Invite::parse("savi://203.0.113.9:7700/lobby?key="), Err(InviteError::MissingKey));
+        assert_eq!(Invite::parse("savi://203.0.113.9:7700/lobby?key=not-a-key"), Err(InviteError::InvalidKey));
+    }
+
+    #[test]
+    fn falls_back_to_the_default_room() {
+        let key = urlencoding::encode(&key()).into_owned();
+        for uri in [format!("savi://203.0.113.9:7700?key={}", key), format!("savi://203.0.113.9:7700/?key={}", key)] {
+            assert_eq!(Invite::parse(&uri).unwrap().room, DEFAULT_ROOM);
+        }
+    }
+
+    #[test]
+    fn refuses_other_links() {
+        assert_eq!(Invite::parse("https://example.org/lobby?key=a"), Err(InviteError::WrongScheme("https".to_string())));
+        assert_eq!(Invite::parse("savi://203.0.113.9/lobby?key=a"), Err(InviteError::MissingAddress));
+        assert!(matches!(Invite::parse("not a link"), Err(InviteError::Malformed(_))));
+    }
+}
@@ -0,0 +1,208 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::signaling::protocol::MIN_PROTOCOL_VERSION;
+
+/// Multicast group the rooms are announced on, in the organization local scope
+pub const LAN_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 83, 86);
+/// Port the announcements are sent to
+pub const LAN_PORT: u16 = 7707;
+/// Time between two announcements of a room
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+/// Time without announcements before a room is taken off the list
+pub const ROOM_TIMEOUT: Duration = Duration::from_secs(7);
+
+/// Start of every announcement, anything else sent to the group is ignored
+const MAGIC: &[u8] = b"SAVI-LAN";
+/// Largest announcement we send or read, they have to fit in a single datagram
+const MAX_ANNOUNCEMENT: usize = 1200;
+
+/// What a host sends to the group about one of its rooms
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoomAnnouncement {
+    pub protocol: u16,
+    pub room: String,
+    /// Username of the host
+    pub username: String,
+    /// Port the host listens on, the address is the one the announcement comes from
+    pub port: u16,
+    /// The room key, only if the host lets anyone in the network join
+    pub key: Option<String>,
+}
+
+impl RoomAnnouncement {
+    fn to_datagram(&self) -> io::Result<Vec<u8>> {
+        let mut datagram = MAGIC.to_vec();
+        datagram.extend(bincode::serialize(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+        if datagram.len() > MAX_ANNOUNCEMENT {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the room name or the username is too long"));
+        }
+        Ok(datagram)
+    }
+
+    fn from_datagram(datagram: &[u8]) -> Option<Self> {
+        let payload = datagram.strip_prefix(MAGIC)?;
+        bincode::deserialize(payload).ok()
+    }
+}
+
+/// A room found on the network
+#[derive(Debug, Clone, PartialEq)]
+pub struct LanRoom {
+    pub room: String,
+    pub username: String,
+    /// ```ip:port``` of the host
+    pub address: String,
+    /// ```None``` if the room key has to be given to join
+    pub key: Option<String>,
+}
+
+/// Announces a room to the network until it is dropped
+pub struct LanAnnouncer {
+    //Dropping it stops the announcements
+    _stop: Sender<()>,
+}
+
+impl LanAnnouncer {
+    /// Starts announcing a room every ```ANNOUNCE_INTERVAL```
+    /// # Arguments
+    /// * `announcement` - The room
+    /// # Errors
+    /// * `io::Error` - If the announcement is too big or no socket can be bound
+    pub fn start(announcement: RoomAnnouncement) -> io::Result<Self> {
+        let datagram = announcement.to_datagram()?;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        //The announcements never leave the network
+        socket.set_multicast_ttl_v4(1)?;
+        let (stop, stopped) = mpsc::channel::<()>();
+        info!("Announcing room {} on the network", announcement.room);
+        thread::spawn(move || loop {
+            if let Err(e) = socket.send_to(&datagram, SocketAddrV4::new(LAN_GROUP, LAN_PORT)) {
+                debug!("Failed to announce room {}: {}", announcement.room, e);
+            }
+            match stopped.recv_timeout(ANNOUNCE_INTERVAL) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                _ => break,
+            }
+        });
+        Ok(LanAnnouncer { _stop: stop })
+    }
+}
+
+/// Keeps the list of the rooms announced on the network until it is dropped
+pub struct LanBrowser {
+    //(address, room) -> (room, last announcement)
+    rooms: Arc<Mutex<HashMap<(String, String), (LanRoom, Instant)>>>,
+    _stop: Sender<()>,
+}
+
+impl LanBrowser {
+    /// Starts listening for announcements. Only one program on the machine can
+    /// listen at a time
+    /// # Errors
+    /// * `io::Error` - If the port is taken or the group can't be joined
+    pub fn start() -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, LAN_PORT))?;
+        socket.join_multicast_v4(&LAN_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        //Wakes up now and then to check if the browser was dropped
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let rooms = Arc::new(Mutex::new(HashMap::new()));
+        let rooms_clone = rooms.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let mut data = [0; MAX_ANNOUNCEMENT];
+            while let Err(mpsc::TryRecvError::Empty) = stopped.try_recv() {
+                let (n, from) = match socket.recv_from(&mut data) {
+                    Ok(received) => received,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                    Err(e) => {
+                        warn!("Stopped looking for rooms on the network: {}", e);
+                        break;
+                    }
+                };
+                let try_announcement = RoomAnnouncement::from_datagram(&data[..n]);
+                if try_announcement.is_none() {
+                    continue;
+                }
+                let announcement = try_announcement.unwrap();
+                if announcement.protocol < MIN_PROTOCOL_VERSION {
+                    debug!("Ignoring room {} of {}, its protocol version {} is too old", announcement.room, from, announcement.protocol);
+                    continue;
+                }
+                let address = SocketAddr::new(from.ip(), announcement.port).to_string();
+                let room = LanRoom {
+                    room: announcement.room,
+                    username: announcement.username,
+                    address: address.clone(),
+                    key: announcement.key,
+                };
+                rooms_clone.lock().unwrap().insert((address, room.room.clone()), (room, Instant::now()));
+            }
+        });
+        Ok(LanBrowser { rooms, _stop: stop })
+    }
+
+    /// Returns the rooms announced within ```ROOM_TIMEOUT```, sorted by name and address
+    pub fn rooms(&self) -> Vec<LanRoom> {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.retain(|_, (_, seen)| seen.elapsed() < ROOM_TIMEOUT);
+        let mut result: Vec<LanRoom> = rooms.values().map(|(room, _)| room.clone()).collect();
+        result.sort_by(|a, b| (&a.room, &a.address).cmp(&(&b.room, &b.address)));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signaling::protocol::PROTOCOL_VERSION;
+
+    fn announcement(key: Option<String>) -> RoomAnnouncement {
+        RoomAnnouncement { protocol: PROTOCOL_VERSION, room: "lobby".to_string(), username: "alice".to_string(), port: 7700, key }
+    }
+
+    #[test]
+    fn reads_back_an_announcement() {
+        for key in [None, Some("key".to_string())] {
+            let datagram = announcement(key.clone()).to_datagram().unwrap();
+            assert!(datagram.starts_with(MAGIC));
+            assert_eq!(RoomAnnouncement::from_datagram(&datagram), Some(announcement(key)));
+        }
+    }
+
+    #[test]
+    fn ignores_packets_without_the_magic() {
+        let datagram = announcement(None).to_datagram().unwrap();
+
+        assert_eq!(RoomAnnouncement::from_datagram(&datagram[MAGIC.len()..]), None);
+        assert_eq!(RoomAnnouncement::from_datagram(&[b"SAVI-NET", &datagram[MAGIC.len()..]].concat()), None);
+        assert_eq!(RoomAnnouncement::from_datagram(b""), None);
+    }
+
+    #[test]
+    fn ignores_truncated_packets() {
+        let datagram = announcement(Some("key".to_string())).to_datagram().unwrap();
+
+        for length in [MAGIC.len() - 1, MAGIC.len(), MAGIC.len() + 3, datagram.len() - 1] {
+            assert_eq!(RoomAnnouncement::from_datagram(&datagram[..length]), None, "{} bytes were read", length);
+        }
+    }
+
+    #[test]
+    fn refuses_to_announce_an_oversized_room() {
+        let mut oversized = announcement(None);
+        oversized.room = "a".repeat(MAX_ANNOUNCEMENT);
+
+        assert_eq!(oversized.to_datagram().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
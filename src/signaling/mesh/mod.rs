@@ -0,0 +1,498 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::aes::AES;
+use crate::audio::playback::AudioPlayback;
+use crate::audio::{Audio, AudioError, DeviceKind};
+use crate::audio_peer::AudioPeer;
+use crate::signaling::{PeerId, BROADCAST, HOST_ID};
+use crate::signaling::chat::{ChatEntry, ChatLog};
+use crate::signaling::discovery::{AddressResolver, Candidate, CandidateKind};
+use crate::signaling::handshake::{self, Handshake, HandshakeAction};
+use crate::signaling::message::{Envelope, Presence, SignalingMessage};
+use crate::signaling::protocol::Capabilities;
+
+/// Time without sending voice before we stop being shown as speaking
+pub const SPEAKING_HOLD: Duration = Duration::from_millis(300);
+/// Time between two checks of ```Mesh::update_speaking```
+pub const SPEAKING_INTERVAL: Duration = Duration::from_millis(100);
+/// Time the connectivity checks with a peer have to find a working candidate pair
+/// before the voice goes through the signaling host instead
+pub const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A remote participant along with the audio link to it
+struct RemotePeer {
+    handshake: Handshake,
+    //Shared so the link can start without holding the peers
+    audio_peer: Arc<AudioPeer>,
+    presence: Presence,
+    //When the connectivity checks started, until a pair is picked or the voice gets relayed
+    linking: Option<Instant>,
+}
+
+/// The local participant view of the call, it negotiates and owns the audio
+/// links to every other peer. Both the signaling server and the client feed it
+/// the messages addressed to them and send back whatever it answers
+pub struct Mesh {
+    id: AtomicU16,
+    room: String,
+    username: String,
+    //(backend, playback device name)
+    playback: Mutex<(String, String)>,
+    peers: Mutex<HashMap<PeerId, RemotePeer>>,
+    chat: ChatLog,
+    presence: Mutex<Presence>,
+    //When we last sent a voice packet
+    last_voice: Mutex<Option<Instant>>,
+    //Finds the address candidates of our audio links
+    resolver: AddressResolver,
+    //The room cipher, the keys of the audio links are derived from it
+    cipher: Arc<AES>,
+    //Why the last audio link failed to start, until it is shown to the user
+    audio_error: Mutex<Option<AudioError>>,
+}
+
+impl Mesh {
+    /// Creates an empty mesh
+    /// # Arguments
+    /// * `id` - Our peer id
+    /// * `room` - The room we are in
+    /// * `username` - Our username
+    /// * `resolver` - Finds the address candidates of our audio links
+    /// * `cipher` - The room cipher
+    pub fn new(id: PeerId, room: String, username: String, resolver: AddressResolver, cipher: Arc<AES>) -> Self {
+        Mesh {
+            id: AtomicU16::new(id),
+            room,
+            username,
+            playback: Mutex::new((String::new(), String::new())),
+            peers: Mutex::new(HashMap::new()),
+            chat: ChatLog::new(),
+            presence: Mutex::new(Presence::default()),
+            last_voice: Mutex::new(None),
+            resolver,
+            cipher,
+            audio_error: Mutex::new(None),
+        }
+    }
+
+    pub fn id(&self) -> PeerId {
+        self.id.load(Ordering::Relaxed)
+    }
+
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    /// Stops every audio link and takes a new id, used when we end up joining the room again as a new peer
+    /// # Arguments
+    /// * `id` - Our new peer id
+    pub fn reset(&self, id: PeerId) {
+        let peers = std::mem::take(&mut *self.peers.lock().unwrap());
+        for peer in peers.values() {
+            peer.audio_peer.stop();
+        }
+        self.id.store(id, Ordering::Relaxed);
+    }
+
+    /// Sets the playback device used for the audio links created from now on
+    /// # Arguments
+    /// * `backend` - The audio backend name
+    /// * `playback_name` - The playback device name
+    pub fn set_playback(&self, backend: String, playback_name: String) {
+        *self.playback.lock().unwrap() = (backend, playback_name);
+    }
+
+    /// Opens the sockets of a new audio link and gathers their candidates, plus the relay
+    /// # Arguments
+    /// * `peer_id` - The peer the link is for
+    /// # Returns
+    /// * `Some((AudioPeer, Vec<Candidate>))` - The audio link and its candidates, best first
+    /// * `None` - If no audio socket can be bound
+    fn gather(&self, peer_id: PeerId) -> Option<(AudioPeer, Vec<Candidate>)> {
+        let audio_peer = match AudioPeer::new(self.resolver.binding()) {
+            Ok(audio_peer) => audio_peer,
+            Err(e) => {
+                error!("No audio socket for peer {}: {}", peer_id, e);
+                return None;
+            }
+        };
+        let mut candidates = self.resolver.candidates(audio_peer.sockets());
+        candidates.push(Candidate::relay());
+        debug!("Candidates for peer {}: {:?}", peer_id, candidates);
+        Some((audio_peer, candidates))
+    }
+
+    /// Starts a negotiation with a peer
+    /// # Arguments
+    /// * `target` - The peer to announce ourselves to
+    /// # Returns
+    /// * `Some(Envelope)` - The ```Announce``` to send
+    /// * `None` - If no audio socket can be bound
+    pub fn announce(&self, target: PeerId) -> Option<Envelope> {
+        let (audio_peer, candidates) = self.gather(target)?;
+        let (handshake, announce) =
+            Handshake::initiate(self.room.clone(), self.username.clone(), candidates, Instant::now(), handshake::DEFAULT_TIMEOUT);
+
+        self.peers.lock().unwrap().insert(target, RemotePeer { handshake, audio_peer: Arc::new(audio_peer), presence: Presence::default(), linking: None });
+        Some(Envelope::new(target, self.id(), announce))
+    }
+
+    /// Handles a message addressed to us
+    /// # Arguments
+    /// * `envelope` - The message
+    /// # Returns
+    /// * `Vec<Envelope>` - The answers to send back
+    pub fn handle(&self, envelope: Envelope) -> Vec<Envelope> {
+        let peer_id = envelope.from;
+        if let SignalingMessage::Chat { username, text } = envelope.message {
+            if !ChatLog::is_valid(&text) {
+                warn!("Dropping chat message of {} bytes from peer {}", text.len(), peer_id);
+                return Vec::new();
+            }
+            let direct = envelope.target != BROADCAST;
+            self.chat.push(ChatEntry { from: peer_id, username, text, direct });
+            return Vec::new();
+        }
+        if let SignalingMessage::Presence { presence } = envelope.message {
+            match self.peers.lock().unwrap().get_mut(&peer_id) {
+                Some(peer) => peer.presence = presence,
+                None => debug!("Got the presence of peer {} but there is no negotiation with it", peer_id),
+            }
+            return Vec::new();
+        }
+        if let SignalingMessage::Voice { packet } = envelope.message {
+            match self.peers.lock().unwrap().get(&peer_id) {
+                //Only its ```Relay``` notice switches us to the relay, the packet isn't authenticated yet
+                Some(peer) => peer.audio_peer.receive(packet),
+                None => debug!("Got a voice packet of peer {} but there is no negotiation with it", peer_id),
+            }
+            return Vec::new();
+        }
+        if envelope.message == SignalingMessage::Relay {
+            if let Some(peer) = self.peers.lock().unwrap().get_mut(&peer_id) {
+                info!("Peer {} can't reach us directly, relaying our voice through the host", peer_id);
+                peer.audio_peer.set_relayed(true);
+                peer.linking = None;
+            }
+            return Vec::new();
+        }
+        if let SignalingMessage::Leave { id } = envelope.message {
+            //Only the server can tell us about someone else leaving
+            if peer_id == HOST_ID || peer_id == id {
+                self.remove_peer(id);
+            }
+            return Vec::new();
+        }
+        if let SignalingMessage::Announce { room, username, candidates, protocol, salt } = envelope.message {
+            if room != self.room {
+                warn!("Peer {} announced itself for room {} but we are in room {}", peer_id, room, self.room);
+                return Vec::new();
+            }
+            let (audio_peer, local_candidates) = match self.gather(peer_id) {
+                Some(gathered) => gathered,
+                None => return Vec::new(),
+            };
+            let try_handshake = Handshake::respond(
+                self.username.clone(),
+                local_candidates,
+                username,
+                candidates,
+                protocol,
+                salt,
+                Instant::now(),
+                handshake::DEFAULT_TIMEOUT,
+            );
+            let (handshake, ack) = match try_handshake {
+                Ok(handshake) => handshake,
+                Err(e) => {
+                    warn!("Ignoring the announce of peer {}: {}", peer_id, e);
+                    return Vec::new();
+                }
+            };
+            self.peers.lock().unwrap().insert(peer_id, RemotePeer { handshake, audio_peer: Arc::new(audio_peer), presence: Presence::default(), linking: None });
+            return vec![Envelope::new(peer_id, self.id(), ack)];
+        }
+
+        let actions = {
+            let mut peers = self.peers.lock().unwrap();
+            let try_peer = peers.get_mut(&peer_id);
+            if try_peer.is_none() {
+                warn!("Got {:?} from peer {} but there is no negotiation with it", envelope.message, peer_id);
+                return Vec::new();
+            }
+            match try_peer.unwrap().handshake.handle(envelope.message, Instant::now()) {
+                Ok(actions) => actions,
+                Err(e) => {
+                    warn!("Handshake with peer {} failed: {}", peer_id, e);
+                    return Vec::new();
+                }
+            }
+        };
+
+        let mut replies = Vec::new();
+        for action in actions {
+            match action {
+                HandshakeAction::Send(message) => replies.push(Envelope::new(peer_id, self.id(), message)),
+                HandshakeAction::Connect { candidates } => replies.extend(self.link(peer_id, candidates)),
+            }
+        }
+        replies
+    }
+
+    /// Starts the audio link with a peer once the negotiation is done. The peers stay
+    /// unlocked while the playback device opens, so the other peers aren't held up
+    /// # Arguments
+    /// * `peer_id` - The peer
+    /// * `candidates` - Its address candidates
+    /// # Returns
+    /// * `Some(Envelope)` - Our presence for the peer
+    /// * `None` - If the link failed to start, the peer left meanwhile or it doesn't support presence
+    fn link(&self, peer_id: PeerId, candidates: Vec<Candidate>) -> Option<Envelope> {
+        let (audio_peer, local, initiator, keys, capabilities) = {
+            let peers = self.peers.lock().unwrap();
+            let peer = peers.get(&peer_id)?;
+            let handshake = &peer.handshake;
+            (peer.audio_peer.clone(), handshake.local_candidates().to_vec(), handshake.is_initiator(), handshake.audio_keys(&self.cipher), handshake.capabilities())
+        };
+        let (backend, playback_name) = self.playback.lock().unwrap().clone();
+        let playback_id = match Audio::get_device_id(backend.clone(), &playback_name, DeviceKind::Playback) {
+            Ok(playback_id) => playback_id,
+            Err(e) => {
+                error!("No audio link with peer {}: {}", peer_id, e);
+                *self.audio_error.lock().unwrap() = Some(e);
+                return None;
+            }
+        };
+        let playback_config = AudioPlayback::create_config(playback_id, 2, 48_000);
+        info!("Checking {} candidates of peer {}", candidates.len(), peer_id);
+        //Read with the peers unlocked, ```set_presence``` takes the locks one at a time too
+        let presence = *self.presence.lock().unwrap();
+        audio_peer.set_muted(presence.deafened);
+        if let Err(e) = audio_peer.connect(local, candidates, initiator, keys, backend, playback_config) {
+            error!("No audio link with peer {}: {}", peer_id, e);
+            *self.audio_error.lock().unwrap() = Some(e);
+            return None;
+        }
+        match self.peers.lock().unwrap().get_mut(&peer_id) {
+            Some(peer) if Arc::ptr_eq(&peer.audio_peer, &audio_peer) => peer.linking = Some(Instant::now()),
+            _ => {
+                debug!("Peer {} left while its audio link was starting", peer_id);
+                audio_peer.stop();
+                return None;
+            }
+        }
+        //The peer only hears about our changes from now on
+        if !capabilities.contains(Capabilities::PRESENCE) {
+            return None;
+        }
+        Some(Envelope::new(peer_id, self.id(), SignalingMessage::Presence { presence }))
+    }
+
+    /// Writes a chat message and keeps it in our history
+    /// # Arguments
+    /// * `target` - The peer to send it to, everyone in the room if ```None```
+    /// * `text` - The message
+    /// # Returns
+    /// * `Some(Envelope)` - The ```Chat``` to send
+    /// * `None` - If the message is empty or too long, or the peer can't chat
+    pub fn chat(&self, target: Option<PeerId>, text: String) -> Option<Envelope> {
+        if !ChatLog::is_valid(&text) {
+            warn!("Not sending a chat message of {} bytes", text.len());
+            return None;
+        }
+        if let Some(id) = target {
+            if !self.capabilities(id).contains(Capabilities::CHAT) {
+                warn!("Not sending a chat message to peer {}, it doesn't support chat", id);
+                return None;
+            }
+        }
+        let id = self.id();
+        let direct = target.is_some();
+        self.chat.push(ChatEntry { from: id, username: self.username.clone(), text: text.clone(), direct });
+        let chat = SignalingMessage::Chat { username: self.username.clone(), text };
+        Some(Envelope::new(target.unwrap_or(BROADCAST), id, chat))
+    }
+
+    /// Returns the chat history of the room, oldest first
+    pub fn get_chat(&self) -> Vec<ChatEntry> {
+        self.chat.history()
+    }
+
+    /// Changes our mute, deafen and away state, deafening stops the playback of every peer
+    /// # Arguments
+    /// * `presence` - Our new state, its speaking flag is ignored
+    /// # Returns
+    /// * `Some(Envelope)` - The ```Presence``` to send to everyone
+    /// * `None` - If nothing changed
+    pub fn set_presence(&self, presence: Presence) -> Option<Envelope> {
+        let updated = {
+            let mut current = self.presence.lock().unwrap();
+            let updated = Presence { speaking: current.speaking && !presence.muted && !presence.deafened, ..presence };
+            if updated == *current {
+                return None;
+            }
+            *current = updated;
+            updated
+        };
+        //The presence is released before locking the peers, ```link``` copies it the same way
+        for peer in self.peers.lock().unwrap().values() {
+            peer.audio_peer.set_muted(updated.deafened);
+        }
+        Some(Envelope::new(BROADCAST, self.id(), SignalingMessage::Presence { presence: updated }))
+    }
+
+    /// Checks if we started or stopped speaking, we are speaking for ```SPEAKING_HOLD```
+    /// after every voice packet we send
+    /// # Arguments
+    /// * `now` - The current time
+    /// # Returns
+    /// * `Some(Envelope)` - The ```Presence``` to send to everyone
+    /// * `None` - If nothing changed
+    pub fn update_speaking(&self, now: Instant) -> Option<Envelope> {
+        let last_voice = *self.last_voice.lock().unwrap();
+        let mut presence = self.presence.lock().unwrap();
+        let recent = last_voice.map(|last| now.saturating_duration_since(last) < SPEAKING_HOLD) == Some(true);
+        let speaking = recent && !presence.muted && !presence.deafened;
+        if presence.speaking == speaking {
+            return None;
+        }
+        presence.speaking = speaking;
+        Some(Envelope::new(BROADCAST, self.id(), SignalingMessage::Presence { presence: *presence }))
+    }
+
+    pub fn get_presence(&self) -> Presence {
+        *self.presence.lock().unwrap()
+    }
+
+    /// The features we and a peer both support
+    /// # Arguments
+    /// * `id` - The peer
+    /// # Returns
+    /// * `Capabilities` - ```NONE``` if the negotiation with the peer didn't get far enough
+    pub fn capabilities(&self, id: PeerId) -> Capabilities {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|peer| peer.handshake.capabilities())
+            .unwrap_or(Capabilities::NONE)
+    }
+
+    /// Stops the audio link with a peer and forgets it
+    /// # Arguments
+    /// * `id` - The peer that left
+    pub fn remove_peer(&self, id: PeerId) {
+        let removed = self.peers.lock().unwrap().remove(&id);
+        if let Some(peer) = removed {
+            info!("Peer {} left", id);
+            peer.audio_peer.stop();
+        }
+    }
+
+    /// Drops every negotiation where the remote peer missed its deadline
+    pub fn expire_handshakes(&self) {
+        let now = Instant::now();
+        self.peers.lock().unwrap().retain(|id, peer| {
+            let expired = peer.handshake.check_timeout(now);
+            if expired {
+                warn!("Negotiation with peer {} timed out", id);
+            }
+            !expired
+        });
+    }
+
+    /// Switches to the relay the peers whose connectivity checks found no working pair
+    /// within ```RELAY_TIMEOUT```
+    /// # Arguments
+    /// * `now` - The current time
+    /// # Returns
+    /// * `Vec<Envelope>` - The ```Relay``` notices to send
+    pub fn relay_unreachable(&self, now: Instant) -> Vec<Envelope> {
+        let mut notices = Vec::new();
+        for (id, peer) in self.peers.lock().unwrap().iter_mut() {
+            let started = match peer.linking {
+                Some(started) => started,
+                None => continue,
+            };
+            if peer.audio_peer.is_ready() {
+                peer.linking = None;
+                continue;
+            }
+            if now.saturating_duration_since(started) < RELAY_TIMEOUT {
+                continue;
+            }
+            peer.linking = None;
+            let relay_candidate = peer.handshake.remote_candidates().iter().any(|candidate| candidate.kind == CandidateKind::Relay);
+            if !relay_candidate || !peer.handshake.capabilities().contains(Capabilities::RELAY) {
+                warn!("Can't reach peer {} directly and it doesn't support relaying", id);
+                continue;
+            }
+            warn!("Can't reach peer {} directly, relaying the voice through the host", id);
+            peer.audio_peer.set_relayed(true);
+            notices.push(Envelope::new(*id, self.id(), SignalingMessage::Relay));
+        }
+        notices
+    }
+
+    /// Sends a voice packet to every peer
+    /// # Arguments
+    /// * `opus_packet` - The packet
+    /// # Returns
+    /// * `Vec<Envelope>` - The ```Voice``` messages for the peers we can't reach directly
+    pub fn send_opus(&self, opus_packet: Vec<u8>) -> Vec<Envelope> {
+        let presence = *self.presence.lock().unwrap();
+        if presence.muted || presence.deafened {
+            return Vec::new();
+        }
+        *self.last_voice.lock().unwrap() = Some(Instant::now());
+        let peers = self.peers.lock().unwrap();
+        debug!("N peers: {}", peers.len());
+        let mut relayed = Vec::new();
+        for (id, peer) in peers.iter() {
+            if peer.audio_peer.is_relayed() {
+                let try_packet = peer.audio_peer.packet(opus_packet.clone());
+                if try_packet.is_none() {
+                    continue;
+                }
+                let packet = try_packet.unwrap();
+                relayed.push(Envelope::new(*id, self.id(), SignalingMessage::Voice { packet }));
+            } else if peer.audio_peer.is_ready() {
+                let _ = peer.audio_peer.send(opus_packet.clone());
+            }
+        }
+        relayed
+    }
+
+    /// Returns why the last audio link failed to start, once
+    pub fn take_audio_error(&self) -> Option<AudioError> {
+        self.audio_error.lock().unwrap().take()
+    }
+
+    /// Returns the id, username, presence and whether the voice is relayed of every peer, sorted by id
+    pub fn get_peers(&self) -> Vec<(PeerId, String, Presence, bool)> {
+        let peers = self.peers.lock().unwrap();
+        let mut result: Vec<(PeerId, String, Presence, bool)> = Vec::new();
+        peers.iter().for_each(|(id, peer)| {
+            result.push((*id, peer.handshake.remote_username().to_string(), peer.presence, peer.audio_peer.is_relayed()));
+        });
+        result.sort_by_key(|(id, _, _, _)| *id);
+        result
+    }
+
+    pub fn change_peer_volume(&self, peer_id: PeerId, volume: u8) {
+        let peers = self.peers.lock().unwrap();
+        let peer = peers.get(&peer_id);
+        if peer.is_none() {
+            error!("Peer {} not found", peer_id);
+            return;
+        }
+        peer.unwrap().audio_peer.change_volume(volume);
+    }
+}
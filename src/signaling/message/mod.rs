@@ -0,0 +1,253 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::aes::AES;
+use crate::signaling::auth::{AuthMessage, Rejection};
+use crate::signaling::discovery::Candidate;
+use crate::signaling::handshake::SALT_SIZE;
+use crate::signaling::heartbeat::Heartbeat;
+use crate::signaling::protocol::{Protocol, MIN_PROTOCOL_VERSION};
+use crate::signaling::{frame, PeerId};
+
+/// A peer in the room as seen by the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Member {
+    pub id: PeerId,
+    /// Address where the peer takes over the signaling if it gets elected as the
+    /// new host, empty if it can't host
+    pub standby: String,
+    /// ```token_digest``` of the resumption token of the peer, a new host only gives
+    /// the peer its id back if it shows the matching token. Empty for the host
+    pub token_digest: String,
+}
+
+/// What a peer lets the others know about its state in the call
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Presence {
+    /// The peer doesn't send its voice
+    pub muted: bool,
+    /// The peer doesn't hear anyone
+    pub deafened: bool,
+    /// The peer stepped away from the call
+    pub away: bool,
+    /// The peer is sending its voice right now
+    pub speaking: bool,
+}
+
+/// Every event that travels through the signaling connection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SignalingMessage {
+    /// First message of every connection, sent by the client
+    /// * `room` - The room to join, the message has to be encrypted with its key
+    /// * `standby` - Address where the client would host the room
+    /// * `resume` - The id the client had before losing its connection or after a host migration
+    /// * `token` - The resumption token that came with that id
+    Join { room: String, standby: String, resume: Option<PeerId>, token: Option<String> },
+    /// Sent by the server to a new connection with the id it was given, the
+    /// peers already in the room, the keepalive settings of the server, the
+    /// token needed to get the same id back after losing the connection and
+    /// the protocol spoken by the server
+    Welcome { id: PeerId, room: String, members: Vec<Member>, heartbeat: Heartbeat, token: String, protocol: Protocol },
+    /// Broadcast by the server when a peer joins, keeps everyone's member list up to date
+    Joined { member: Member },
+    /// A new peer introduces itself, its address candidates, the protocol it speaks
+    /// and its half of the salt of the audio keys
+    Announce { room: String, username: String, candidates: Vec<Candidate>, protocol: Protocol, salt: [u8; SALT_SIZE] },
+    /// Answer to an ```Announce``` with the username, address candidates, protocol and
+    /// salt of the announced peer
+    Ack { username: String, candidates: Vec<Candidate>, protocol: Protocol, salt: [u8; SALT_SIZE] },
+    /// The announcer got the ```Ack```, the announced peer can start the audio connection
+    Ok,
+    /// The announced peer started the audio connection, the announcer can do the same
+    Ko,
+    /// A peer left the room. Sent by a client to hang up and broadcast by the server
+    /// to everyone else when a peer hangs up or its connection closes
+    Leave { id: PeerId },
+    /// Sent by the server every heartbeat interval to check the connection is alive
+    Ping,
+    /// Answer of the client to a ```Ping```
+    Pong,
+    /// A chat message, for everyone in the room if sent to ```BROADCAST``` or for a single peer
+    Chat { username: String, text: String },
+    /// The state of a peer changed, sent to everyone in the room. It is also sent to
+    /// each peer once the audio connection with it is up
+    Presence { presence: Presence },
+    /// The audio connection with the receiver never came up, the sender
+    /// switched to sending its voice through the host
+    Relay,
+    /// A voice packet sent through the host, as it would have been sent over UDP
+    Voice { packet: Vec<u8> },
+}
+
+/// A ```SignalingMessage``` along with its routing information
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Envelope {
+    /// The peer that should receive the message, ```HOST_ID``` is the server
+    /// and ```BROADCAST``` is everyone in the room
+    pub target: PeerId,
+    /// The peer that sent the message
+    pub from: PeerId,
+    pub message: SignalingMessage,
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    /// The underlying stream failed
+    Io(std::io::Error),
+    /// The message could not be decrypted with the room key
+    Decrypt,
+    /// The message was decrypted but is not a valid ```Envelope```
+    Malformed(bincode::Error),
+    /// The message is valid but it was not expected at this point
+    Unexpected(SignalingMessage),
+    /// The authentication message was not expected at this point
+    UnexpectedAuth(AuthMessage),
+    /// The server refused to let us in
+    Rejected(Rejection),
+    /// The server speaks a protocol version older than the oldest one we can talk to
+    Incompatible(u16),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::Io(e) => write!(f, "signaling stream error: {}", e),
+            MessageError::Decrypt => write!(f, "message could not be decrypted, wrong key?"),
+            MessageError::Malformed(e) => write!(f, "malformed message: {}", e),
+            MessageError::Unexpected(message) => write!(f, "unexpected message {:?}", message),
+            MessageError::UnexpectedAuth(message) => write!(f, "unexpected authentication message {:?}", message),
+            MessageError::Rejected(reason) => write!(f, "the server refused the connection: {}", reason),
+            MessageError::Incompatible(version) => write!(
+                f,
+                "the server speaks protocol version {} but this version of Savi needs at least version {}, the server has to be updated",
+                version, MIN_PROTOCOL_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+impl From<std::io::Error> for MessageError {
+    fn from(e: std::io::Error) -> Self {
+        MessageError::Io(e)
+    }
+}
+
+impl Envelope {
+    pub fn new(target: PeerId, from: PeerId, message: SignalingMessage) -> Self {
+        Envelope { target, from, message }
+    }
+
+    /// Serializes and encrypts the envelope
+    /// # Arguments
+    /// * `cipher` - The room cipher
+    pub fn seal(&self, cipher: &AES) -> Vec<u8> {
+        let serialized = bincode::serialize(self).unwrap();
+        cipher.encrypt_bytes(&serialized)
+    }
+
+    /// Decrypts and deserializes an envelope
+    /// # Arguments
+    /// * `payload` - A frame payload as produced by ```Envelope::seal```
+    /// * `cipher` - The room cipher
+    /// # Errors
+    /// * `MessageError::Decrypt` - If the payload can't be decrypted with the cipher key
+    /// * `MessageError::Malformed` - If the decrypted payload is not an envelope
+    pub fn open(payload: &[u8], cipher: &AES) -> Result<Envelope, MessageError> {
+        let decrypted = cipher.decrypt_bytes(payload).map_err(|_| MessageError::Decrypt)?;
+        bincode::deserialize(&decrypted).map_err(MessageError::Malformed)
+    }
+}
+
+/// Encrypts an envelope and writes it as a single frame
+/// # Arguments
+/// * `writer` - The stream to write to
+/// * `cipher` - The room cipher
+/// * `envelope` - The message to send
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, cipher: &AES, envelope: &Envelope) -> Result<(), MessageError> {
+    debug!("Sending {:?}", envelope);
+    frame::write_frame(writer, &envelope.seal(cipher)).await?;
+    Ok(())
+}
+
+/// Reads a frame and decrypts it into an envelope
+/// # Arguments
+/// * `reader` - The stream to read from
+/// * `cipher` - The room cipher
+/// # Returns
+/// * `Some((Envelope, Vec<u8>))` - The envelope and the raw frame payload, used to relay it as is
+/// * `None` - If the stream was closed cleanly
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R, cipher: &AES) -> Result<Option<(Envelope, Vec<u8>)>, MessageError> {
+    let payload = match frame::read_frame(reader).await? {
+        Some(payload) => payload,
+        None => return Ok(None),
+    };
+    let envelope = Envelope::open(&payload, cipher)?;
+    debug!("Got {:?}", envelope);
+    Ok(Some((envelope, payload)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signaling::frame::encode_frame;
+    use crate::signaling::{BROADCAST, HOST_ID};
+    use std::io::ErrorKind;
+
+    fn chat() -> Envelope {
+        Envelope::new(BROADCAST, HOST_ID, SignalingMessage::Chat { username: "alice".to_string(), text: "hello".to_string() })
+    }
+
+    #[test]
+    fn opens_a_sealed_envelope() {
+        let cipher = AES::new(None).unwrap();
+
+        assert_eq!(Envelope::open(&chat().seal(&cipher), &cipher).unwrap(), chat());
+    }
+
+    #[test]
+    fn refuses_an_envelope_sealed_with_another_key() {
+        let sealed = chat().seal(&AES::new(None).unwrap());
+
+        assert!(matches!(Envelope::open(&sealed, &AES::new(None).unwrap()), Err(MessageError::Decrypt)));
+    }
+
+    #[tokio::test]
+    async fn reads_a_written_message() {
+        let cipher = AES::new(None).unwrap();
+        let mut stream = Vec::new();
+        write_message(&mut stream, &cipher, &chat()).await.unwrap();
+
+        let mut reader = stream.as_slice();
+        let (envelope, payload) = read_message(&mut reader, &cipher).await.unwrap().unwrap();
+        assert_eq!(envelope, chat());
+        assert_eq!(Envelope::open(&payload, &cipher).unwrap(), chat());
+        assert!(read_message(&mut reader, &cipher).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_a_truncated_message() {
+        let cipher = AES::new(None).unwrap();
+        let frame = encode_frame(&chat().seal(&cipher)).unwrap();
+
+        let error = read_message(&mut &frame[..frame.len() - 1], &cipher).await.unwrap_err();
+        assert!(matches!(error, MessageError::Io(e) if e.kind() == ErrorKind::UnexpectedEof));
+    }
+
+    #[tokio::test]
+    async fn reports_garbage_instead_of_panicking() {
+        let cipher = AES::new(None).unwrap();
+        let garbage = encode_frame(&[0xAB; 64]).unwrap();
+        let not_an_envelope = encode_frame(&cipher.encrypt_bytes(b"garbage")).unwrap();
+        let empty = encode_frame(b"").unwrap();
+
+        assert!(matches!(read_message(&mut garbage.as_slice(), &cipher).await, Err(MessageError::Decrypt)));
+        assert!(matches!(read_message(&mut not_an_envelope.as_slice(), &cipher).await, Err(MessageError::Malformed(_))));
+        assert!(matches!(read_message(&mut empty.as_slice(), &cipher).await, Err(MessageError::Decrypt)));
+    }
+}
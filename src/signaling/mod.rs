@@ -1,34 +1,32 @@
 // SPDX-FileCopyrightText: Copyright 2023 Savi
 // SPDX-License-Identifier: GPL-3.0-only 
 
+pub mod auth;
+pub mod chat;
+//Both take part in calls, the headless server only needs the rest
+#[cfg(feature = "audio")]
 pub mod client;
+pub mod discovery;
+pub mod error;
+pub mod frame;
+pub mod handshake;
+pub mod heartbeat;
+pub mod ids;
+pub mod invite;
+pub mod lan;
+#[cfg(feature = "audio")]
+pub mod mesh;
+pub mod message;
+pub mod protocol;
+pub mod room;
 pub mod server;
+pub mod transport;
 
-use std::net::ToSocketAddrs;
-use std::net::UdpSocket;
-use stunclient::StunClient;
+pub use error::SignalingError;
 
-pub fn get_address_ipv6() -> String {
-    let stun_addr = "stun.l.google.com:19302"
-        .to_socket_addrs()
-        .unwrap()
-        .filter(|x| x.is_ipv6())
-        .next()
-        .unwrap();
-    let udp = UdpSocket::bind("[::]:0").unwrap();
-    let mut c = StunClient::new(stun_addr);
-    c.set_software(Some("Savi"));
-    c.query_external_address(&udp).unwrap().to_string()
-}
-pub fn get_address_ipv4() -> String {
-    let stun_addr = "stun.l.google.com:19302"
-        .to_socket_addrs()
-        .unwrap()
-        .filter(|x| x.is_ipv4())
-        .next()
-        .unwrap();
-    let udp = UdpSocket::bind("[::]:0").unwrap();
-    let mut c = StunClient::new(stun_addr);
-    c.set_software(Some("Savi"));
-    c.query_external_address(&udp).unwrap().to_string()
-}
+/// Identifies a peer inside a room
+pub type PeerId = u16;
+/// The peer hosting the signaling server
+pub const HOST_ID: PeerId = 0;
+/// Target of the messages for everyone in the room, never given to a peer
+pub const BROADCAST: PeerId = PeerId::MAX;
@@ -0,0 +1,158 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::signaling::message::SignalingMessage;
+
+/// Version of the signaling protocol spoken by this build. It goes up with every
+/// change to the authentication, the handshake or the layout of the messages.
+/// Version 1 was the original plain text protocol, version 2 sent a single address
+/// candidate instead of the list of candidates, version 3 sent the voice unencrypted,
+/// version 4 didn't share the resumption tokens of the members with a new host
+pub const PROTOCOL_VERSION: u16 = 5;
+/// Oldest version this build can still talk to
+pub const MIN_PROTOCOL_VERSION: u16 = 5;
+
+/// Set of optional features a peer supports. Unknown bits sent by newer peers are
+/// kept as they are, they just never match anything on our side
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Audio encoded with Opus at 48 kHz
+    pub const OPUS: Capabilities = Capabilities(1 << 0);
+    /// Signaling encrypted with AES-256-GCM-SIV
+    pub const AES_GCM_SIV: Capabilities = Capabilities(1 << 1);
+    /// ```Chat``` messages
+    pub const CHAT: Capabilities = Capabilities(1 << 2);
+    /// ```Presence``` messages
+    pub const PRESENCE: Capabilities = Capabilities(1 << 3);
+    /// Voice relayed by the signaling host with ```Relay``` and ```Voice``` messages
+    pub const RELAY: Capabilities = Capabilities(1 << 4);
+    /// What a peer must support to set up an audio link at all
+    pub const REQUIRED: Capabilities = Capabilities(Capabilities::OPUS.0 | Capabilities::AES_GCM_SIV.0);
+
+    const NAMES: [(Capabilities, &'static str); 5] = [
+        (Capabilities::OPUS, "opus"),
+        (Capabilities::AES_GCM_SIV, "aes-gcm-siv"),
+        (Capabilities::CHAT, "chat"),
+        (Capabilities::PRESENCE, "presence"),
+        (Capabilities::RELAY, "relay"),
+    ];
+
+    /// Everything this build supports
+    pub fn local() -> Self {
+        Capabilities::OPUS | Capabilities::AES_GCM_SIV | Capabilities::CHAT | Capabilities::PRESENCE | Capabilities::RELAY
+    }
+
+    /// Checks if every feature of ```other``` is in the set
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The features both sets have
+    pub fn intersection(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+
+    /// The feature needed to carry a message, ```NONE``` for the ones every version knows
+    /// # Arguments
+    /// * `message` - The message to send
+    pub fn required(message: &SignalingMessage) -> Self {
+        match message {
+            SignalingMessage::Chat { .. } => Capabilities::CHAT,
+            SignalingMessage::Presence { .. } => Capabilities::PRESENCE,
+            SignalingMessage::Relay | SignalingMessage::Voice { .. } => Capabilities::RELAY,
+            _ => Capabilities::NONE,
+        }
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = Capabilities::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// What a peer tells the others about itself before anything else
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Protocol {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl Protocol {
+    /// The protocol spoken by this build
+    pub fn local() -> Self {
+        Protocol {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::local(),
+        }
+    }
+
+    /// Checks a remote peer can talk to us. A newer peer that can't talk to us
+    /// anymore refuses us on its side, so only its version is checked here
+    /// # Arguments
+    /// * `remote` - What the remote peer sent
+    /// # Returns
+    /// * `Ok(Capabilities)` - The features both peers support, only those can be used
+    /// * `Err(u16)` - The version of the remote peer if it is too old
+    pub fn negotiate(&self, remote: &Protocol) -> Result<Capabilities, u16> {
+        if remote.version < MIN_PROTOCOL_VERSION {
+            return Err(remote.version);
+        }
+        Ok(self.capabilities.intersection(remote.capabilities))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn talks_to_older_and_newer_peers() {
+        let newer = Protocol { version: PROTOCOL_VERSION + 1, capabilities: Capabilities::local() };
+        let oldest = Protocol { version: MIN_PROTOCOL_VERSION, capabilities: Capabilities::REQUIRED };
+
+        assert_eq!(newer.negotiate(&oldest), Ok(Capabilities::REQUIRED));
+        assert_eq!(Protocol::local().negotiate(&newer), Ok(Capabilities::local()));
+    }
+
+    #[test]
+    fn refuses_a_version_below_the_minimum() {
+        let old = Protocol { version: MIN_PROTOCOL_VERSION - 1, capabilities: Capabilities::local() };
+
+        assert_eq!(Protocol::local().negotiate(&old), Err(MIN_PROTOCOL_VERSION - 1));
+    }
+
+    #[test]
+    fn only_keeps_the_capabilities_both_peers_have() {
+        let no_chat = Protocol { version: PROTOCOL_VERSION, capabilities: Capabilities::REQUIRED | Capabilities::PRESENCE };
+        let capabilities = Protocol::local().negotiate(&no_chat).unwrap();
+
+        assert_eq!(capabilities, Capabilities::REQUIRED | Capabilities::PRESENCE);
+        assert!(!capabilities.contains(Capabilities::CHAT));
+        assert_eq!(Capabilities::CHAT.intersection(Capabilities::RELAY), Capabilities::NONE);
+        assert_eq!(capabilities.to_string(), "opus, aes-gcm-siv, presence");
+    }
+}
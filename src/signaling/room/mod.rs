@@ -0,0 +1,525 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use log::{debug, error, info, warn};
+
+use crate::aes::AES;
+use crate::signaling::{frame, PeerId, BROADCAST, HOST_ID};
+use crate::signaling::auth::{self, token_digest, AuthMessage, Rejection};
+use crate::signaling::heartbeat::Heartbeat;
+use crate::signaling::ids::IdAllocator;
+#[cfg(feature = "audio")]
+use crate::signaling::mesh::Mesh;
+use crate::signaling::protocol::{Capabilities, Protocol};
+use crate::signaling::transport::{BoxedStream, Reader};
+use crate::signaling::message::{self, Envelope, Member, MessageError, SignalingMessage};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::Notify;
+
+/// Time a peer has to reconnect and get its id back after losing its connection,
+/// or after a host migration
+pub const RESUME_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// What a new connection asked for in its ```Join```
+pub struct JoinRequest {
+    /// Address where the peer would host the room
+    pub standby: String,
+    /// The id the peer had before losing its connection
+    pub resume: Option<PeerId>,
+    /// The resumption token that came with that id
+    pub token: Option<String>,
+    /// The features both the peer and the server support
+    pub capabilities: Capabilities,
+}
+
+/// The server side of a peer connection
+struct Connection {
+    //Tells this connection apart from a newer one of the same peer
+    session: u64,
+    //Resumption token given to the peer in the welcome
+    token: String,
+    address: Option<IpAddr>,
+    //Only the messages the peer understands are relayed to it
+    capabilities: Capabilities,
+    //Frames waiting to be written to the peer
+    outgoing: UnboundedSender<Vec<u8>>,
+    //Stops the tasks serving the connection
+    close: Arc<Notify>,
+}
+
+/// A peer that lost its connection and can still get its id back
+struct Suspended {
+    //```token_digest``` of the resumption token, the previous host shared it for its peers
+    token_digest: String,
+    address: Option<IpAddr>,
+    deadline: Instant,
+}
+
+/// A named room hosted by the signaling server. Every room has its own key,
+/// members and id space, peers of different rooms never see each other
+pub struct Room {
+    name: String,
+    cipher: Arc<AES>,
+    connections: Mutex<HashMap<PeerId, Connection>>,
+    sessions: AtomicU64,
+    members: Mutex<HashMap<PeerId, Member>>,
+    ids: Mutex<IdAllocator>,
+    //Peers that haven't reconnected yet, the other peers still see them in the room
+    resumable: Mutex<HashMap<PeerId, Suspended>>,
+    //Addresses of the peers that were dropped for good
+    banned: Mutex<HashSet<IpAddr>>,
+    //Our audio links, only in the room we take part in
+    #[cfg(feature = "audio")]
+    mesh: Option<Arc<Mesh>>,
+}
+
+impl Room {
+    /// Creates an empty room we don't take part in
+    /// # Arguments
+    /// * `name` - The room name
+    /// * `cipher` - The room cipher
+    pub fn new(name: String, cipher: Arc<AES>) -> Self {
+        Room {
+            name,
+            cipher,
+            connections: Mutex::new(HashMap::new()),
+            sessions: AtomicU64::new(0),
+            members: Mutex::new(HashMap::new()),
+            ids: Mutex::new(IdAllocator::new()),
+            resumable: Mutex::new(HashMap::new()),
+            banned: Mutex::new(HashSet::new()),
+            #[cfg(feature = "audio")]
+            mesh: None,
+        }
+    }
+
+    /// Creates a room we take part in, we are its first member
+    /// # Arguments
+    /// * `name` - The room name
+    /// * `cipher` - The room cipher
+    /// * `mesh` - Our audio links
+    #[cfg(feature = "audio")]
+    pub fn with_mesh(name: String, cipher: Arc<AES>, mesh: Arc<Mesh>) -> Self {
+        let room = Room::new(name, cipher);
+        room.ids.lock().unwrap().reserve(mesh.id());
+        //The host can't take over its own room or resume its id, so it has no standby address or token
+        let host = Member { id: mesh.id(), standby: String::new(), token_digest: String::new() };
+        room.members.lock().unwrap().insert(mesh.id(), host);
+        Room { mesh: Some(mesh), ..room }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn cipher(&self) -> &AES {
+        &self.cipher
+    }
+
+    /// Keeps the ids of the peers of a host that left, as long as they reconnect
+    /// within ```RESUME_TIMEOUT``` with the token the previous host gave them
+    /// # Arguments
+    /// * `members` - The other peers of the room, without the previous host
+    pub fn resume(&self, members: Vec<Member>) {
+        let deadline = Instant::now() + RESUME_TIMEOUT;
+        let mut ids = self.ids.lock().unwrap();
+        let mut roster = self.members.lock().unwrap();
+        let mut resumable = self.resumable.lock().unwrap();
+        for member in members {
+            ids.reserve(member.id);
+            let suspended = Suspended { token_digest: member.token_digest.clone(), address: None, deadline };
+            resumable.insert(member.id, suspended);
+            roster.insert(member.id, member);
+        }
+    }
+
+    /// Welcomes a new connection and relays its messages until it closes
+    /// # Arguments
+    /// * `stream` - The new connection, it was authenticated and its ```Join``` was already read
+    /// * `address` - The address of the peer, used to ban it
+    /// * `request` - The contents of the ```Join```
+    /// * `heartbeat` - The keepalive settings of the server
+    pub async fn serve_connection(&self, mut stream: BoxedStream, address: Option<IpAddr>, request: JoinRequest, heartbeat: Heartbeat) {
+        if address.map(|address| self.banned.lock().unwrap().contains(&address)) == Some(true) {
+            warn!("Refusing banned address {:?} in room {}", address, self.name);
+            let _ = auth::write_auth(&mut stream, &AuthMessage::Rejected { reason: Rejection::Banned }).await;
+            return;
+        }
+        let try_id = self.admit(request.resume, request.token);
+        if try_id.is_none() {
+            error!("Room {} is full, dropping connection", self.name);
+            let _ = auth::write_auth(&mut stream, &AuthMessage::Rejected { reason: Rejection::Full }).await;
+            return;
+        }
+        let id = try_id.unwrap();
+        let session = self.sessions.fetch_add(1, Ordering::Relaxed);
+        let token = format!("{:032x}", rand::random::<u128>());
+        let member = Member { id, standby: request.standby, token_digest: token_digest(&token) };
+        let (reader, mut writer) = tokio::io::split(stream);
+
+        //The member list lets the client know who it has to announce itself to
+        let members = self.members.lock().unwrap().values().filter(|m| m.id != id).cloned().collect();
+        let welcome = Envelope::new(id, self.host_id(), SignalingMessage::Welcome {
+            id,
+            room: self.name.clone(),
+            members,
+            heartbeat,
+            token: token.clone(),
+            protocol: Protocol::local(),
+        });
+        let try_welcome = match auth::write_auth(&mut writer, &AuthMessage::Accepted).await {
+            Ok(()) => message::write_message(&mut writer, &self.cipher, &welcome).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = try_welcome {
+            error!("Failed to welcome peer {}: {}", id, e);
+            self.suspend(id, token, address);
+            return;
+        }
+
+        let (outgoing, mut queue) = mpsc::unbounded_channel::<Vec<u8>>();
+        let close = Arc::new(Notify::new());
+        let writer_close = close.clone();
+        tokio::spawn(async move {
+            while let Some(payload) = queue.recv().await {
+                if let Err(e) = frame::write_frame(&mut writer, &payload).await {
+                    error!("Failed to send message to peer {}, closing connection: {}", id, e);
+                    writer_close.notify_one();
+                    break;
+                }
+            }
+            let _ = writer.shutdown().await;
+        });
+
+        {
+            let mut connections = self.connections.lock().unwrap();
+            for (other, connection) in connections.iter() {
+                let joined = Envelope::new(*other, HOST_ID, SignalingMessage::Joined { member: member.clone() });
+                let _ = connection.outgoing.send(joined.seal(&self.cipher));
+            }
+            let connection = Connection {
+                session,
+                token,
+                address,
+                capabilities: request.capabilities,
+                outgoing: outgoing.clone(),
+                close: close.clone(),
+            };
+            connections.insert(id, connection);
+        }
+        self.members.lock().unwrap().insert(id, member);
+        info!("Peer {} joined room {} from {:?}", id, self.name, address);
+
+        let last_seen = Mutex::new(Instant::now());
+        let hung_up = tokio::select! {
+            hung_up = self.relay(id, reader, outgoing.clone(), &last_seen) => hung_up,
+            _ = self.keep_alive(id, outgoing, &last_seen, heartbeat) => false,
+            _ = close.notified() => {
+                debug!("Closing connection of peer {}", id);
+                false
+            }
+        };
+        self.disconnect(id, session, hung_up);
+    }
+
+    /// Pings a peer every heartbeat interval and returns once it hasn't answered for the heartbeat timeout
+    /// # Arguments
+    /// * `id` - The peer id
+    /// * `outgoing` - The queue of frames for the peer
+    /// * `last_seen` - When the last message of the peer arrived
+    /// * `heartbeat` - The keepalive settings
+    async fn keep_alive(&self, id: PeerId, outgoing: UnboundedSender<Vec<u8>>, last_seen: &Mutex<Instant>, heartbeat: Heartbeat) {
+        let mut ticker = tokio::time::interval(heartbeat.interval);
+        loop {
+            ticker.tick().await;
+            let silence = last_seen.lock().unwrap().elapsed();
+            if silence >= heartbeat.timeout {
+                warn!("Peer {} didn't answer for {:?}, dropping it", id, silence);
+                return;
+            }
+            let ping = Envelope::new(id, HOST_ID, SignalingMessage::Ping);
+            let _ = outgoing.send(ping.seal(&self.cipher));
+        }
+    }
+
+    /// The id the welcome comes from, our peer id if we take part in the room
+    #[cfg(feature = "audio")]
+    fn host_id(&self) -> PeerId {
+        self.mesh.as_ref().map(|mesh| mesh.id()).unwrap_or(HOST_ID)
+    }
+    #[cfg(not(feature = "audio"))]
+    fn host_id(&self) -> PeerId {
+        HOST_ID
+    }
+
+    /// Hands a message to our audio links
+    /// # Returns
+    /// * `Ok(Vec<Envelope>)` - The answers of the mesh
+    /// * `Err(Envelope)` - The message back, if we don't take part in the room
+    #[cfg(feature = "audio")]
+    async fn to_mesh(&self, envelope: Envelope) -> Result<Vec<Envelope>, Envelope> {
+        let mesh = match self.mesh.clone() {
+            Some(mesh) => mesh,
+            None => return Err(envelope),
+        };
+        //The mesh may query STUN servers and open audio devices
+        Ok(tokio::task::spawn_blocking(move || mesh.handle(envelope)).await.unwrap_or_default())
+    }
+    #[cfg(not(feature = "audio"))]
+    async fn to_mesh(&self, envelope: Envelope) -> Result<Vec<Envelope>, Envelope> {
+        Err(envelope)
+    }
+
+    /// Gives an id to a new connection. Peers that lost their connection get their
+    /// previous id back if they show the token that came with it
+    /// # Arguments
+    /// * `resume` - The previous id of the peer
+    /// * `token` - The resumption token of that id
+    fn admit(&self, resume: Option<PeerId>, token: Option<String>) -> Option<PeerId> {
+        if let Some(id) = resume {
+            {
+                let mut resumable = self.resumable.lock().unwrap();
+                //Peers of a previous host without a token can't claim an id, it could be anyone's
+                let digest = token.as_deref().map(token_digest);
+                let matches = resumable.get(&id).map(|suspended| digest.as_ref() == Some(&suspended.token_digest));
+                if matches == Some(true) {
+                    resumable.remove(&id);
+                    info!("Peer {} came back", id);
+                    return Some(id);
+                }
+            }
+            //We may not have noticed the old connection is dead yet
+            let mut connections = self.connections.lock().unwrap();
+            if token.is_some() && connections.get(&id).map(|connection| &connection.token) == token.as_ref() {
+                let stale = connections.remove(&id).unwrap();
+                stale.close.notify_one();
+                info!("Peer {} came back on a new connection", id);
+                return Some(id);
+            }
+            warn!("Peer {} can't resume its id, giving it a new one", id);
+        }
+        self.ids.lock().unwrap().allocate()
+    }
+
+    /// Handles the messages of a peer until its connection closes or it hangs up
+    /// # Arguments
+    /// * `id` - The peer id
+    /// * `reader` - The read half of the peer connection
+    /// * `outgoing` - The queue of frames for the peer
+    /// * `last_seen` - Updated every time a message of the peer arrives
+    /// # Returns
+    /// * `bool` - ```true``` if the peer hung up, ```false``` if the connection was lost
+    async fn relay(&self, id: PeerId, mut reader: Reader, outgoing: UnboundedSender<Vec<u8>>, last_seen: &Mutex<Instant>) -> bool {
+        loop {
+            let (envelope, payload) = match message::read_message(&mut reader, &self.cipher).await {
+                Ok(Some(message)) => message,
+                Ok(None) => {
+                    info!("Connection closed");
+                    return false;
+                }
+                Err(MessageError::Io(e)) => {
+                    error!("Failed to read frame, closing connection: {}", e);
+                    return false;
+                }
+                Err(e) => {
+                    warn!("Dropping message from peer {}: {}", id, e);
+                    continue;
+                }
+            };
+            *last_seen.lock().unwrap() = Instant::now();
+            if envelope.message == SignalingMessage::Pong {
+                continue;
+            }
+            if envelope.from != id {
+                warn!("Peer {} tried to send a message as peer {}", id, envelope.from);
+                continue;
+            }
+            let target_id = envelope.target;
+            if target_id == HOST_ID && matches!(envelope.message, SignalingMessage::Leave { .. }) {
+                info!("Peer {} hung up", id);
+                return true;
+            }
+            let required = Capabilities::required(&envelope.message);
+            if target_id == BROADCAST {
+                {
+                    let connections = self.connections.lock().unwrap();
+                    for (other, connection) in connections.iter() {
+                        if *other != id && connection.capabilities.contains(required) {
+                            let _ = connection.outgoing.send(payload.clone());
+                        }
+                    }
+                }
+                let _ = self.to_mesh(envelope).await;
+            } else if target_id == HOST_ID || target_id == self.host_id() {
+                let replies = match self.to_mesh(envelope).await {
+                    Ok(replies) => replies,
+                    Err(envelope) => {
+                        debug!("Dropping {:?} from peer {}, we don't take part in room {}", envelope.message, id, self.name);
+                        continue;
+                    }
+                };
+                for reply in replies {
+                    let _ = outgoing.send(reply.seal(&self.cipher));
+                }
+            } else {
+                let connections = self.connections.lock().unwrap();
+                let try_target = connections.get(&target_id);
+                if try_target.is_none() {
+                    debug!("Peer not found");
+                    continue;
+                }
+                let target = try_target.unwrap();
+                if !target.capabilities.contains(required) {
+                    debug!("Dropping {:?} for peer {}, it doesn't support {}", envelope.message, target_id, required);
+                    continue;
+                }
+                if target.outgoing.send(payload).is_err() {
+                    error!(
+                        "Failed to send message to peer, connection is probably closed"
+                    );
+                    //The target frees its id once its own tasks stop
+                    target.close.notify_one();
+                }
+            }
+        }
+    }
+
+    /// Sends a message of ours to a peer of the room, or to everyone if it is for ```BROADCAST```.
+    /// Peers that don't support the message don't get it
+    /// # Arguments
+    /// * `envelope` - The message
+    pub fn deliver(&self, envelope: &Envelope) {
+        let payload = envelope.seal(&self.cipher);
+        let required = Capabilities::required(&envelope.message);
+        let connections = self.connections.lock().unwrap();
+        if envelope.target == BROADCAST {
+            for connection in connections.values().filter(|connection| connection.capabilities.contains(required)) {
+                let _ = connection.outgoing.send(payload.clone());
+            }
+        } else if let Some(connection) = connections.get(&envelope.target) {
+            if connection.capabilities.contains(required) {
+                let _ = connection.outgoing.send(payload);
+            }
+        } else {
+            debug!("Peer {} not found", envelope.target);
+        }
+    }
+
+    /// Cleans up after a connection closes, only the task serving the connection calls this.
+    /// A peer that didn't hang up keeps its id for ```RESUME_TIMEOUT``` in case it reconnects
+    /// # Arguments
+    /// * `id` - The peer id
+    /// * `session` - The session of the connection that closed
+    /// * `hung_up` - If the peer left on purpose
+    fn disconnect(&self, id: PeerId, session: u64, hung_up: bool) {
+        let connection = {
+            let mut connections = self.connections.lock().unwrap();
+            match connections.get(&id) {
+                Some(connection) if connection.session == session => connections.remove(&id).unwrap(),
+                //The peer is already on a new connection, was dropped or the room was closed
+                _ => return,
+            }
+        };
+        if hung_up {
+            self.forget(id);
+        } else {
+            self.suspend(id, connection.token, connection.address);
+        }
+    }
+
+    /// Keeps the id of a peer that lost its connection without telling anyone
+    fn suspend(&self, id: PeerId, token: String, address: Option<IpAddr>) {
+        info!("Lost the connection with peer {}, keeping its id for {:?}", id, RESUME_TIMEOUT);
+        let suspended = Suspended {
+            token_digest: token_digest(&token),
+            address,
+            deadline: Instant::now() + RESUME_TIMEOUT,
+        };
+        self.resumable.lock().unwrap().insert(id, suspended);
+    }
+
+    /// Lets everyone know a peer left and frees its id
+    fn forget(&self, id: PeerId) {
+        info!("Peer {} left room {}", id, self.name);
+        self.members.lock().unwrap().remove(&id);
+        for (member, connection) in self.connections.lock().unwrap().iter() {
+            let leave = Envelope::new(*member, HOST_ID, SignalingMessage::Leave { id });
+            let _ = connection.outgoing.send(leave.seal(&self.cipher));
+        }
+        #[cfg(feature = "audio")]
+        if let Some(mesh) = self.mesh.as_ref() {
+            mesh.remove_peer(id);
+        }
+        self.ids.lock().unwrap().release(id);
+    }
+
+    /// Removes a peer from the room and closes its connection. The peer is told it
+    /// was dropped so it doesn't try to reconnect
+    /// # Arguments
+    /// * `id` - The peer to drop
+    /// * `ban` - Also refuse new connections from its address for as long as the room is hosted
+    /// # Returns
+    /// * `bool` - ```false``` if the peer is not in the room
+    pub fn kick(&self, id: PeerId, ban: bool) -> bool {
+        let removed = self.connections.lock().unwrap().remove(&id);
+        let address = match removed {
+            Some(connection) => {
+                let leave = Envelope::new(id, HOST_ID, SignalingMessage::Leave { id });
+                let _ = connection.outgoing.send(leave.seal(&self.cipher));
+                connection.close.notify_one();
+                connection.address
+            }
+            None => match self.resumable.lock().unwrap().remove(&id) {
+                Some(suspended) => suspended.address,
+                None => return false,
+            },
+        };
+        info!("Dropping peer {} from room {}", id, self.name);
+        if ban {
+            match address {
+                Some(address) => {
+                    info!("Banning {} from room {}", address, self.name);
+                    self.banned.lock().unwrap().insert(address);
+                }
+                None => warn!("The address of peer {} is unknown, it can't be banned", id),
+            }
+        }
+        self.forget(id);
+        true
+    }
+
+    /// Gives up on the peers that didn't reconnect in time
+    pub fn expire_resumes(&self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.resumable.lock().unwrap().retain(|id, suspended| {
+            if now >= suspended.deadline {
+                expired.push(*id);
+                return false;
+            }
+            true
+        });
+        for id in expired {
+            warn!("Peer {} didn't come back", id);
+            self.forget(id);
+        }
+    }
+
+    /// Closes every connection, the peers elect a new host among themselves
+    pub fn hang_up(&self) {
+        let connections = std::mem::take(&mut *self.connections.lock().unwrap());
+        let host = self.host_id();
+        for (id, connection) in connections.iter() {
+            //So the peer elects a new host right away instead of trying to reconnect
+            let leave = Envelope::new(*id, HOST_ID, SignalingMessage::Leave { id: host });
+            let _ = connection.outgoing.send(leave.seal(&self.cipher));
+            connection.close.notify_one();
+        }
+    }
+}
@@ -1,229 +1,440 @@
 // SPDX-FileCopyrightText: Copyright 2023 Savi
 // SPDX-License-Identifier: GPL-3.0-only
 
-use log::{debug, error, info, warn};
-use serde::de::Error;
+use log::{error, info, warn};
 
 use crate::aes::AES;
-use crate::audio::Audio;
-use crate::audio::playback::AudioPlayback;
-use crate::audio_peer::AudioPeer;
-use crate::{signaling};
+#[cfg(feature = "audio")]
+use crate::audio::AudioError;
+use crate::signaling::{PeerId, SignalingError};
+#[cfg(feature = "audio")]
+use crate::signaling::HOST_ID;
+use crate::signaling::auth::{self, AuthMessage, Rejection};
+#[cfg(feature = "audio")]
+use crate::signaling::chat::ChatEntry;
+#[cfg(feature = "audio")]
+use crate::signaling::discovery::AddressResolver;
+use crate::signaling::heartbeat::Heartbeat;
+#[cfg(feature = "audio")]
+use crate::signaling::mesh::{self, Mesh};
+use crate::signaling::message::{self, Envelope, MessageError, SignalingMessage};
+#[cfg(feature = "audio")]
+use crate::signaling::message::{Member, Presence};
+use crate::signaling::protocol::{Protocol, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::signaling::room::{JoinRequest, Room};
+use crate::signaling::transport::{self, BoxedStream};
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::TcpListener;
-use std::net::TcpStream;
+use std::net::{IpAddr, TcpListener, ToSocketAddrs};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::time::Duration;
+#[cfg(feature = "audio")]
+use std::time::Instant;
+use tokio::net::TcpStream;
+#[cfg(feature = "audio")]
+use tokio::runtime::Runtime;
+use tokio::sync::Notify;
+
+pub use crate::signaling::room::RESUME_TIMEOUT;
+
+/// Time a new connection has to prove it knows the room key and send its ```Join```
+pub const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Room used when the user doesn't pick one
+pub const DEFAULT_ROOM: &str = "lobby";
 
 pub struct SignalingServer {
-    username: String,
     listener: TcpListener,
-    cipher: Arc<AES>,
-    streams: Arc<Mutex<HashMap<u8, TcpStream>>>,
-    audio_peers: Arc<Mutex<HashMap<u8, (String, String, AudioPeer)>>>,
+    //Address the peers reach the listener at, none when running headless
+    advertised: Option<String>,
+    //Takes the same signaling over WebSocket, for peers behind HTTP proxies
+    websocket: Option<TcpListener>,
+    rooms: Mutex<HashMap<String, Arc<Room>>>,
+    //The room we take part in, none when running headless
+    room: Option<Arc<Room>>,
+    #[cfg(feature = "audio")]
+    mesh: Option<Arc<Mesh>>,
+    heartbeat: Heartbeat,
+    //Stops the accept loop
+    shutdown: Notify,
 }
 impl SignalingServer {
-    pub fn new(username: String) -> Self {
-        let bind = signaling::get_address_ipv6();
-        let listener = TcpListener::bind(bind).unwrap();
+    /// Hosts a new room with a random key
+    /// # Arguments
+    /// * `username` - Our username
+    /// * `room` - The name of our room
+    /// * `resolver` - Where we listen, the address we advertise and our address candidates
+    /// # Errors
+    /// * `SignalingError::Io` - If we can't listen on the bind address
+    #[cfg(feature = "audio")]
+    pub fn new(username: String, room: String, resolver: AddressResolver) -> Result<Self, SignalingError> {
+        let listener = resolver.binding().listen()?;
+        let advertised = resolver.advertise(&listener)?;
+
+        let cipher = Arc::new(AES::new(None)?);
+        let mesh = Arc::new(Mesh::new(HOST_ID, room, username, resolver, cipher.clone()));
+        Ok(Self::with_parts(listener, advertised, cipher, mesh))
+    }
+
+    /// Creates a server without rooms that only relays signaling between the peers,
+    /// it doesn't take part in any call. Rooms are added with ```create_room```
+    /// # Arguments
+    /// * `address` - The address to listen on
+    pub fn headless<A: ToSocketAddrs>(address: A) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        Ok(SignalingServer {
+            listener,
+            advertised: None,
+            websocket: None,
+            rooms: Mutex::new(HashMap::new()),
+            room: None,
+            #[cfg(feature = "audio")]
+            mesh: None,
+            heartbeat: Heartbeat::default(),
+            shutdown: Notify::new(),
+        })
+    }
+
+    /// Takes over the room of a host that left, the peers of the room keep their
+    /// ids as long as they reconnect within ```RESUME_TIMEOUT```
+    /// # Arguments
+    /// * `listener` - The standby listener
+    /// * `advertised` - The standby address the peers were told about
+    /// * `cipher` - The room cipher
+    /// * `mesh` - Our audio links, they stay up during the migration
+    /// * `members` - The other peers of the room, without the previous host
+    #[cfg(feature = "audio")]
+    pub fn promote(listener: TcpListener, advertised: String, cipher: Arc<AES>, mesh: Arc<Mesh>, members: Vec<Member>) -> Self {
+        let server = Self::with_parts(listener, advertised, cipher, mesh);
+        if let Some(room) = server.room.as_ref() {
+            room.resume(members);
+        }
+        server
+    }
 
-        let cipher = Arc::new(AES::new(None));
+    #[cfg(feature = "audio")]
+    fn with_parts(listener: TcpListener, advertised: String, cipher: Arc<AES>, mesh: Arc<Mesh>) -> Self {
+        let name = mesh.room().to_string();
+        let room = Arc::new(Room::with_mesh(name.clone(), cipher, mesh.clone()));
         SignalingServer {
-            username,
             listener,
-            cipher,
-            streams: Arc::new(Mutex::new(HashMap::new())),
-            audio_peers: Arc::new(Mutex::new(HashMap::new())),
+            advertised: Some(advertised),
+            websocket: None,
+            rooms: Mutex::new(HashMap::from([(name, room.clone())])),
+            room: Some(room),
+            mesh: Some(mesh),
+            heartbeat: Heartbeat::default(),
+            shutdown: Notify::new(),
+        }
+    }
+
+    /// Replaces the default keepalive settings of the connections
+    /// # Arguments
+    /// * `heartbeat` - The new settings, only used by the connections accepted from now on
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Also accepts connections over WebSocket. TLS is left to a reverse proxy in
+    /// front of the server, the clients dial ```wss://``` to the proxy
+    /// # Arguments
+    /// * `address` - The address to listen on for WebSocket connections
+    pub fn with_websocket<A: ToSocketAddrs>(mut self, address: A) -> std::io::Result<Self> {
+        self.websocket = Some(TcpListener::bind(address)?);
+        Ok(self)
+    }
+
+    /// Hosts another room, we don't take part in it
+    /// # Arguments
+    /// * `name` - The room name
+    /// * `key` - The room key, a random one is generated if ```None```
+    /// # Returns
+    /// * `Some(String)` - The key of the new room
+    /// * `None` - If there is a room with that name already or the key is not valid
+    pub fn create_room(&self, name: String, key: Option<String>) -> Option<String> {
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.contains_key(&name) {
+            return None;
         }
+        let cipher = Arc::new(AES::new(key).ok()?);
+        let key = cipher.get_key().clone();
+        info!("Hosting room {}", name);
+        rooms.insert(name.clone(), Arc::new(Room::new(name, cipher)));
+        Some(key)
     }
+
     pub fn get_listen_address(&self) -> String {
         self.listener.local_addr().unwrap().to_string()
     }
+    pub fn get_listen_port(&self) -> u16 {
+        self.listener.local_addr().unwrap().port()
+    }
+    /// Returns the address the peers reach us at, the listen address when running headless
+    pub fn get_address(&self) -> String {
+        self.advertised.clone().unwrap_or_else(|| self.get_listen_address())
+    }
+    pub fn get_websocket_address(&self) -> Option<String> {
+        self.websocket.as_ref().and_then(|listener| listener.local_addr().ok()).map(|address| address.to_string())
+    }
     pub fn get_cipher_key(&self) -> String {
-        self.cipher.get_key().clone()
+        self.room.as_ref().map(|room| room.cipher().get_key().clone()).unwrap_or_default()
+    }
+    pub fn get_room(&self) -> String {
+        self.room.as_ref().map(|room| room.name().to_string()).unwrap_or_default()
     }
-    pub fn run(&self, backend:String ,playback_name: String) {
-        let listener_tryclone = self.listener.try_clone();
-        if listener_tryclone.is_err() {
-            panic!("Failed to clone listener");
+    /// Serves connections until ```hang_up``` is called, blocks the calling thread
+    /// # Arguments
+    /// * `backend` - The audio backend name
+    /// * `playback_name` - The playback device for our audio links
+    /// # Errors
+    /// * `SignalingError::Io` - If the async runtime can't start or the listeners can't be used
+    #[cfg(feature = "audio")]
+    pub fn run(self: &Arc<Self>, backend:String ,playback_name: String) -> Result<(), SignalingError> {
+        if let Some(mesh) = self.mesh.as_ref() {
+            mesh.set_playback(backend, playback_name);
         }
-        //let listener_clone = listener_tryclone.unwrap();
-        //let cipher_mainclone = self.cipher.clone();
-        //let peers_mainclone = self.streams.clone();
-        thread::scope(|scope| {
-            info!("Listening for connections");
-            let bkc = backend.clone();
-            loop {
-                let bck = bkc.clone();
-                let (mut stream, _) = self.listener.accept().unwrap();
-                let mut stream_clone = stream.try_clone().unwrap();
-                info!("New connection");
-
-                let peers = self.streams.clone();
-
-                let id = peers.lock().unwrap().len() as u8 + 1;
-                let welcome_msg = format!("id¬{}", id);
-                let encrypted_welcome = self.cipher.encrypt(welcome_msg);
-
-                //The id also lets the client know how many adress candidates has to create
-                stream.write_all(encrypted_welcome.as_bytes()).unwrap();
-
-                self.streams
-                    .lock()
-                    .unwrap()
-                    .insert(id, stream.try_clone().unwrap());
-                let peers = self.streams.clone();
-                let playback_clone = playback_name.clone();
-                scope.spawn(move || {
-                    let audio_peers = self.audio_peers.clone();
-                    let bkk = bck.clone();
-                    loop {
-                        let backend = bkk.clone();
-                        let buf = &mut [0; 2048];
-                        let size = stream_clone.read(buf).unwrap();
-                        if size == 0 {
-                            info!("Connection closed");
-                            peers.lock().unwrap().remove(&id);
-                            break;
+        let rt = Runtime::new()?;
+        rt.block_on(self.clone().serve())
+    }
+
+    /// Accepts and serves connections until ```hang_up``` is called, the mesh playback has to be set already
+    /// # Errors
+    /// * `SignalingError::Io` - If the listeners can't be used
+    pub async fn serve(self: Arc<Self>) -> Result<(), SignalingError> {
+        let listener = to_async(&self.listener)?;
+        let websocket = self.websocket.as_ref().map(to_async).transpose()?;
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        let upkeep = self.keep_mesh();
+        tokio::pin!(upkeep);
+        info!("Listening for connections");
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, address) = match accepted {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            error!("Failed to accept connection: {}", e);
+                            continue;
                         }
-                        let encrypted = String::from_utf8_lossy(&buf[0..size]).to_string();
-                        debug!("Encrypted: {}", encrypted);
-                        let decrypted = self.cipher.decrypt(encrypted);
-                        debug!("Decrypted: {}", decrypted);
-                        //<target_id>¬<from_id>¬<event>¬<args>
-                        let split: Vec<&str> = decrypted.split("¬").collect();
-                        let target_id = split[0].parse::<u8>().unwrap();
-                        if target_id == 0 {
-                            let event = split[2];
-                            match event {
-                                "ann" => {
-                                    let peer_id = split[1].parse::<u8>().unwrap();
-                                    let peer_username = split[3].to_string();
-                                    let peer_address_candidate = split[4].to_string();
-            
-                                    let adress_candidate = signaling::get_address_ipv6();
-                                    let username = self.username.clone();
-            
-                                    let mut peers = audio_peers.lock().unwrap();
-                                    let audio_peer = AudioPeer::new(adress_candidate.clone());
-                                    let item = (peer_username, peer_address_candidate, audio_peer);
-                                    peers.insert(peer_id, item);
-            
-                                    let ack = format!(
-                                        "{}¬{}¬ack¬{}¬{}",
-                                        peer_id, 0, username, adress_candidate
-                                    );
-                                    let encrypted = self.cipher.encrypt(ack);
-                                    debug!("Sending encrypted {}", encrypted);
-                                    stream.write(encrypted.as_bytes()).unwrap();
-                                }
-                                "ack" => {
-                                    let peer_id = split[1].parse::<u8>().unwrap();
-                                    let username = split[3];
-                                    let address_candidate = split[4];
-            
-                                    let mut peers = audio_peers.lock().unwrap();
-                                    peers.get_mut(&peer_id).unwrap().0 = username.to_string();
-                                    peers.get_mut(&peer_id).unwrap().1 = address_candidate.to_string();
-            
-                                    let ok = format!("{}¬{}¬ok", peer_id, 0);
-                                    let encrypted = self.cipher.encrypt(ok);
-                                    debug!("Sending encrypted {}", encrypted);
-                                    stream.write(encrypted.as_bytes()).unwrap();
-                                }
-                                "ok" => {
-                                    let peer_id = split[1].parse::<u8>().unwrap();
-            
-                                    let ok = format!("{}¬{}¬ko", peer_id, 0);
-                                    let encrypted = self.cipher.encrypt(ok);
-                                    debug!("Sending encrypted {}", encrypted);
-                                    stream.write(encrypted.as_bytes()).unwrap();
-            
-                                    let audio_peers_clone = audio_peers.clone();
-                                    let playback_clone = playback_clone.clone();
-                                    scope.spawn(move ||{
-                                        let peers = audio_peers_clone.lock().unwrap();
-                                        let item = peers.get(&peer_id);
-                                        let (_, address, peer) = item.unwrap();
-            
-                                        let playback_id = Audio::get_device_id(backend.clone(), &playback_clone, crate::audio::DeviceKind::Playback).unwrap();
-                                        let playback_config = AudioPlayback::create_config(playback_id, 2, 48_000);
-                                        peer.connect(address.clone(), backend.clone() ,playback_config);
-                                        loop{
-                                            thread::sleep(std::time::Duration::from_millis(1));
-                                        }
-                                        
-                                    }); 
-                                }
-                                "ko" => {
-                                    let peer_id = split[1].parse::<u8>().unwrap();
-            
-                                    let audio_peers_clone = audio_peers.clone();
-                                    let playback_clone = playback_clone.clone();
-                                    scope.spawn(move ||{
-                                        let peers = audio_peers_clone.lock().unwrap();
-                                        let item = peers.get(&peer_id);
-                                        let (_, address, peer) = item.unwrap();
-            
-                                        let playback_id = Audio::get_device_id(backend.clone(), &playback_clone, crate::audio::DeviceKind::Playback).unwrap();
-                                        let playback_config = AudioPlayback::create_config(playback_id, 2, 48_000);
-                                        peer.connect(address.clone(), backend.clone(), playback_config);
-                                        loop{
-                                            thread::sleep(std::time::Duration::from_millis(1));
-                                        }
-                                        
-                                    }); 
-                                }
-                                _ => {
-                                    error!("Unknown event {}", event);
-                                }
-                            }
-                        } else {
-                            let mut list = peers.lock().unwrap();
-                            let try_stream = list.get(&target_id);
-                            if try_stream.is_none() {
-                                debug!("Peer not found");
-                                continue;
-                            }
-                            let mut target = try_stream.unwrap();
-                            //let mut target = stream.try_clone().unwrap();
-                            let try_send = target.write(&buf[0..size]);
-                            if try_send.is_err() {
-                                error!(
-                                    "Failed to send message to peer, connection is probably closed"
-                                );
-                                list.remove(&target_id);
-                                break;
-                            }
+                    };
+                    info!("New connection from {}", address);
+                    let _ = stream.set_nodelay(true);
+                    let server = self.clone();
+                    tokio::spawn(async move { server.serve_connection(Box::new(stream), address.ip()).await });
+                }
+                accepted = accept(websocket.as_ref()) => {
+                    let (stream, address) = match accepted {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            error!("Failed to accept WebSocket connection: {}", e);
+                            continue;
                         }
+                    };
+                    info!("New WebSocket connection from {}", address);
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        match tokio::time::timeout(JOIN_TIMEOUT, transport::accept_websocket(stream, address)).await {
+                            Ok(Ok((stream, peer))) => server.serve_connection(stream, peer).await,
+                            Ok(Err(e)) => warn!("WebSocket handshake with {} failed: {}", address, e),
+                            Err(_) => warn!("WebSocket handshake with {} timed out", address),
+                        }
+                    });
+                }
+                _ = ticker.tick() => {
+                    let rooms: Vec<Arc<Room>> = self.rooms.lock().unwrap().values().cloned().collect();
+                    for room in rooms {
+                        room.expire_resumes();
                     }
-                });
+                }
+                _ = &mut upkeep => {}
+                _ = self.shutdown.notified() => break,
             }
-        });
+        }
+        info!("Stopped listening for connections");
+        Ok(())
     }
-    pub fn send_opus(&self, opus_packet: Vec<u8>) {
-        let peers = self.audio_peers.lock().unwrap();
-        for (_, _, peer) in peers.values() {
-            peer.send(opus_packet.clone());
+
+    /// Keeps the audio links of our room going: drops the stuck handshakes, relays the voice
+    /// of the peers that can't be reached and tells everyone when we start or stop speaking.
+    /// Never returns
+    #[cfg(feature = "audio")]
+    async fn keep_mesh(&self) {
+        let (room, mesh) = match (self.room.as_ref(), self.mesh.as_ref()) {
+            (Some(room), Some(mesh)) => (room, mesh),
+            _ => return std::future::pending().await,
+        };
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        let mut speaking = tokio::time::interval(mesh::SPEAKING_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    mesh.expire_handshakes();
+                    for notice in mesh.relay_unreachable(Instant::now()) {
+                        room.deliver(&notice);
+                    }
+                }
+                _ = speaking.tick() => {
+                    if let Some(presence) = mesh.update_speaking(Instant::now()) {
+                        room.deliver(&presence);
+                    }
+                }
+            }
         }
     }
-    pub fn get_peers(&self) -> Vec<(u8, String)> {
-        let peers = self.audio_peers.lock().unwrap();
-        let mut result: Vec<(u8,String)> = Vec::new();
-        peers.iter().for_each(|(id, (username, _, _))| {
-            result.push((*id, username.clone()));
-        });
-        result
-    }
-    pub fn change_peer_volume(&self, peer_id: u8, volume: u8){
-        let peers = self.audio_peers.lock().unwrap();
-        let peer = peers.get(&peer_id);
-        if peer.is_none(){
-            error!("Peer {} not found", peer_id);
+    #[cfg(not(feature = "audio"))]
+    async fn keep_mesh(&self) {
+        std::future::pending().await
+    }
+
+    /// Authenticates a new connection and hands it over to the room it asked for
+    /// # Arguments
+    /// * `stream` - The new connection
+    /// * `address` - The address of the peer
+    async fn serve_connection(&self, mut stream: BoxedStream, address: IpAddr) {
+        let nonce = auth::challenge();
+        let challenge = AuthMessage::Challenge { protocol: Protocol::local(), nonce: nonce.clone() };
+        if let Err(e) = auth::write_auth(&mut stream, &challenge).await {
+            warn!("Failed to challenge the connection: {}", e);
             return;
         }
-        let (_, _, peer) = peer.unwrap();
-        peer.change_volume(volume);
+        let (room, request) = match tokio::time::timeout(JOIN_TIMEOUT, self.authenticate(&mut stream, &nonce)).await {
+            Ok(Ok(join)) => join,
+            Ok(Err(e)) => {
+                warn!("Refusing connection: {}", e);
+                return;
+            }
+            Err(_) => {
+                warn!("The connection didn't join any room in time, dropping it");
+                return;
+            }
+        };
+        room.serve_connection(stream, Some(address), request, self.heartbeat).await;
+    }
+
+    /// Checks the answer of a new connection to its challenge and reads its ```Join```
+    /// # Arguments
+    /// * `stream` - The new connection, the challenge was sent already
+    /// * `nonce` - The challenge
+    /// # Returns
+    /// * `(Arc<Room>, JoinRequest)` - The room and what the peer asked for
+    /// # Errors
+    /// * `MessageError::Rejected` - If the connection speaks a protocol we can't talk to or doesn't
+    /// know the key of the room it asked for, it was told so already
+    async fn authenticate(&self, stream: &mut BoxedStream, nonce: &[u8]) -> Result<(Arc<Room>, JoinRequest), MessageError> {
+        let (protocol, name, proof) = match auth::read_auth(stream).await? {
+            Some(AuthMessage::Response { protocol, room, proof }) => (protocol, room, proof),
+            Some(message) => return Err(MessageError::UnexpectedAuth(message)),
+            None => return Err(MessageError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+        };
+        let capabilities = match Protocol::local().negotiate(&protocol) {
+            Ok(capabilities) => capabilities,
+            Err(version) => {
+                warn!("The connection speaks protocol version {}, we need at least version {}", version, MIN_PROTOCOL_VERSION);
+                let reason = Rejection::Incompatible { version: PROTOCOL_VERSION, oldest: MIN_PROTOCOL_VERSION };
+                let _ = auth::write_auth(stream, &AuthMessage::Rejected { reason }).await;
+                return Err(MessageError::Rejected(reason));
+            }
+        };
+        let try_room = self.rooms.lock().unwrap().get(&name).cloned();
+        let room = match try_room {
+            Some(room) if auth::verify(room.cipher(), nonce, &name, &proof) => room,
+            //Unknown rooms get the same answer so their names don't leak
+            _ => {
+                let rejected = AuthMessage::Rejected { reason: Rejection::WrongKey };
+                let _ = auth::write_auth(stream, &rejected).await;
+                return Err(MessageError::Rejected(Rejection::WrongKey));
+            }
+        };
+        match message::read_message(stream, room.cipher()).await? {
+            Some((Envelope { message: SignalingMessage::Join { room: joined, standby, resume, token }, .. }, _)) if joined == name => {
+                Ok((room, JoinRequest { standby, resume, token, capabilities }))
+            }
+            Some((envelope, _)) => Err(MessageError::Unexpected(envelope.message)),
+            None => Err(MessageError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+        }
+    }
+
+    /// Stops listening and closes every connection, the peers elect a new host among themselves
+    pub fn hang_up(&self) {
+        self.shutdown.notify_one();
+        for room in self.rooms.lock().unwrap().values() {
+            room.hang_up();
+        }
+    }
+    #[cfg(feature = "audio")]
+    pub fn send_opus(&self, opus_packet: Vec<u8>) {
+        if let (Some(room), Some(mesh)) = (self.room.as_ref(), self.mesh.as_ref()) {
+            for voice in mesh.send_opus(opus_packet) {
+                room.deliver(&voice);
+            }
+        }
+    }
+    #[cfg(feature = "audio")]
+    pub fn get_peers(&self) -> Vec<(PeerId, String, Presence, bool)> {
+        self.mesh.as_ref().map(|mesh| mesh.get_peers()).unwrap_or_default()
+    }
+    /// Returns why the last audio link failed to start, once
+    #[cfg(feature = "audio")]
+    pub fn take_audio_error(&self) -> Option<AudioError> {
+        self.mesh.as_ref().and_then(|mesh| mesh.take_audio_error())
+    }
+    #[cfg(feature = "audio")]
+    pub fn change_peer_volume(&self, peer_id: PeerId, volume: u8){
+        if let Some(mesh) = self.mesh.as_ref() {
+            mesh.change_peer_volume(peer_id, volume);
+        }
+    }
+
+    /// Drops a peer from our room, everyone else stops its audio link
+    /// # Arguments
+    /// * `id` - The peer to drop
+    /// * `ban` - Keep its address out of the room until we stop hosting it
+    /// # Returns
+    /// * `bool` - ```false``` if the peer is not in the room
+    pub fn kick(&self, id: PeerId, ban: bool) -> bool {
+        self.room.as_ref().map(|room| room.kick(id, ban)).unwrap_or(false)
+    }
+
+    /// Sends a chat message to our room
+    /// # Arguments
+    /// * `target` - The peer to send it to, everyone in the room if ```None```
+    /// * `text` - The message
+    #[cfg(feature = "audio")]
+    pub fn send_chat(&self, target: Option<PeerId>, text: String) {
+        if let (Some(room), Some(mesh)) = (self.room.as_ref(), self.mesh.as_ref()) {
+            if let Some(chat) = mesh.chat(target, text) {
+                room.deliver(&chat);
+            }
+        }
+    }
+    #[cfg(feature = "audio")]
+    pub fn get_chat(&self) -> Vec<ChatEntry> {
+        self.mesh.as_ref().map(|mesh| mesh.get_chat()).unwrap_or_default()
+    }
+
+    /// Changes our mute, deafen and away state and lets everyone in our room know
+    /// # Arguments
+    /// * `presence` - Our new state, its speaking flag is ignored
+    #[cfg(feature = "audio")]
+    pub fn set_presence(&self, presence: Presence) {
+        if let (Some(room), Some(mesh)) = (self.room.as_ref(), self.mesh.as_ref()) {
+            if let Some(update) = mesh.set_presence(presence) {
+                room.deliver(&update);
+            }
+        }
+    }
+}
+
+fn to_async(listener: &TcpListener) -> std::io::Result<tokio::net::TcpListener> {
+    let listener = listener.try_clone()?;
+    listener.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(listener)
+}
+
+/// Accepts a connection, never returns if there is no listener
+async fn accept(listener: Option<&tokio::net::TcpListener>) -> std::io::Result<(TcpStream, std::net::SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
     }
 }
@@ -0,0 +1,134 @@
+// SPDX-FileCopyrightText: Copyright 2023 Savi
+// SPDX-License-Identifier: GPL-3.0-only
+
+use futures_util::{SinkExt, StreamExt};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// A signaling connection, the frames are the same whatever carries them
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+pub type BoxedStream = Box<dyn Stream>;
+pub type Reader = ReadHalf<BoxedStream>;
+pub type Writer = WriteHalf<BoxedStream>;
+
+/// Checks if an address is a ```ws://``` or ```wss://``` URL instead of a ```host:port```
+pub fn is_websocket(address: &str) -> bool {
+    address.starts_with("ws://") || address.starts_with("wss://")
+}
+
+/// Connects to a signaling server
+/// # Arguments
+/// * `address` - A ```host:port``` for raw TCP or a ```ws://```/```wss://``` URL
+pub async fn connect(address: &str) -> Result<BoxedStream, Error> {
+    if is_websocket(address) {
+        let (websocket, _) = tokio_tungstenite::connect_async(address).await.map_err(to_io)?;
+        return Ok(Box::new(WebSocket::new(websocket)));
+    }
+    let stream = TcpStream::connect(address).await?;
+    stream.set_nodelay(true)?;
+    Ok(Box::new(stream))
+}
+
+/// Runs the WebSocket handshake on a new connection.
+/// Connections coming from the same machine are assumed to go through a reverse
+/// proxy, their address is taken from the ```X-Forwarded-For``` header if there is one
+/// # Arguments
+/// * `stream` - The accepted connection
+/// * `address` - Its peer address
+/// # Returns
+/// * `(BoxedStream, IpAddr)` - The connection and the address of the peer
+pub async fn accept_websocket(stream: TcpStream, address: SocketAddr) -> Result<(BoxedStream, IpAddr), Error> {
+    let mut forwarded = None;
+    let callback = |request: &Request, response: Response| {
+        forwarded = request
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.split(',').next())
+            .and_then(|client| client.trim().parse::<IpAddr>().ok());
+        Ok(response)
+    };
+    let websocket = tokio_tungstenite::accept_hdr_async(stream, callback).await.map_err(to_io)?;
+    let peer = match forwarded {
+        Some(client) if address.ip().is_loopback() => client,
+        _ => address.ip(),
+    };
+    Ok((Box::new(WebSocket::new(websocket)), peer))
+}
+
+fn to_io(e: tungstenite::Error) -> Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => Error::from(ErrorKind::ConnectionReset),
+        e => Error::new(ErrorKind::Other, e),
+    }
+}
+
+/// Carries the signaling byte stream in binary WebSocket messages
+struct WebSocket<S> {
+    inner: WebSocketStream<S>,
+    //The message being read and how much of it was read already
+    incoming: Vec<u8>,
+    position: usize,
+}
+
+impl<S> WebSocket<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        WebSocket {
+            inner,
+            incoming: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocket<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        loop {
+            if this.position < this.incoming.len() {
+                let size = buf.remaining().min(this.incoming.len() - this.position);
+                buf.put_slice(&this.incoming[this.position..this.position + size]);
+                this.position += size;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.inner.poll_next_unpin(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    this.incoming = data;
+                    this.position = 0;
+                }
+                //Nothing more to read
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                //Pings are answered by tungstenite, text is not part of the protocol
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(to_io(e))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        ready!(this.inner.poll_ready_unpin(cx)).map_err(to_io)?;
+        this.inner.start_send_unpin(Message::Binary(buf.to_vec())).map_err(to_io)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.get_mut().inner.poll_flush_unpin(cx).map_err(to_io)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.get_mut().inner.poll_close_unpin(cx).map_err(to_io)
+    }
+}